@@ -5,13 +5,16 @@ use std::sync::OnceLock;
 
 use ab_glyph::FontRef;
 use include_dir::{include_dir, Dir};
-use log::{info, LevelFilter, trace};
-use serde::Deserialize;
+use log::{info, LevelFilter, trace, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::runtime::{Builder, Runtime};
 use std::io::Write;
 use crate::cmd::Cmd;
 use crate::config::time::current_formatted_time;
+use crate::http::acme;
+use crate::http::forwarded::init_trusted_proxies;
+use crate::http::garbage::{init_garbage, GarbageMode};
 
 pub mod time;
 
@@ -54,7 +57,254 @@ pub struct ServerConfig {
     pub database_file : Option<String>,
     pub enable_tls : bool,
     pub tls_cert_file : String,
-    pub tls_key_file : String
+    pub tls_key_file : String,
+    #[serde(default)]
+    pub proxy_protocol : bool,
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies : Vec<String>,
+    #[serde(default = "default_client_ip_header")]
+    pub client_ip_header : String,
+    #[serde(default = "default_garbage_mode")]
+    pub garbage_mode : String,
+    #[serde(default = "default_garbage_chunk_size")]
+    pub garbage_chunk_size : usize,
+    #[serde(default = "default_max_download_bytes")]
+    pub max_download_bytes : u64,
+    #[serde(default = "default_max_download_duration")]
+    pub max_download_duration : u64,
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period : u64,
+    #[serde(default)]
+    pub tls_certificates : Vec<TlsCertificate>,
+    #[serde(default)]
+    pub listeners : Vec<ListenerConfig>,
+    #[serde(default)]
+    pub servers : Vec<SpeedtestServer>,
+    #[serde(default)]
+    pub cors : CorsConfig,
+    #[serde(default)]
+    pub limits : LimitsConfig,
+    #[serde(default)]
+    pub client_auth : ClientAuthConfig,
+    #[serde(default)]
+    pub acme : AcmeConfig,
+    #[serde(default)]
+    pub request_limits : RequestLimitsConfig,
+    #[serde(default)]
+    pub metrics : MetricsConfig
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allowed_origins : Vec<String>,
+    pub allow_headers : Vec<String>,
+    pub allow_credentials : bool,
+    pub max_age : u32
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allow_headers: vec!["Content-Encoding".to_string(),"Content-Type".to_string()],
+            allow_credentials: false,
+            max_age: 86400,
+        }
+    }
+}
+
+/*Bound address, without any `[[listeners]]` one listener is made of bind_address, listen_port & enable_tls*/
+#[derive(Deserialize, Debug, Clone)]
+pub struct ListenerConfig {
+    pub address : String,
+    pub port : u16,
+    #[serde(default)]
+    pub tls : bool,
+    // empty uses the top level tls_cert_file & tls_key_file
+    #[serde(default)]
+    pub tls_cert_file : String,
+    #[serde(default)]
+    pub tls_key_file : String,
+    #[serde(default)]
+    pub proxy_protocol : bool,
+    // answer every request with a redirect to the first tls listener
+    #[serde(default)]
+    pub redirect_to_https : bool
+}
+
+/*Additional certificate served to clients asking for one of its names with SNI*/
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsCertificate {
+    pub cert_file : String,
+    pub key_file : String
+}
+
+/*TLS client certificates verified against a CA bundle, empty ca_file disables client authentication*/
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ClientAuthConfig {
+    pub ca_file : String,
+    // reject tls handshakes without a valid client certificate, on every route
+    pub required : bool,
+    // routes only served to verified clients, plain http connections are refused on them
+    pub routes : Vec<String>
+}
+
+impl Default for ClientAuthConfig {
+    fn default() -> Self {
+        ClientAuthConfig {
+            ca_file: String::new(),
+            required: false,
+            routes: vec!["stats".to_string()],
+        }
+    }
+}
+
+/*Request sizes & timeouts in seconds against oversized or slow clients, a 0 timeout is disabled*/
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct RequestLimitsConfig {
+    pub max_request_line : usize,
+    pub max_header_count : usize,
+    // request line excluded
+    pub max_header_size : usize,
+    // urlencoded & multipart bodies kept in memory, upload bodies are discarded & not limited
+    pub max_form_body_size : u64,
+    // from the first byte of a request to the end of its headers, tls handshake included
+    pub header_timeout : u64,
+    // without receiving any body data
+    pub body_timeout : u64,
+    // idle connection waiting for its next request
+    pub keep_alive_timeout : u64
+}
+
+impl Default for RequestLimitsConfig {
+    fn default() -> Self {
+        RequestLimitsConfig {
+            max_request_line: 8192,
+            max_header_count: 100,
+            max_header_size: 16384,
+            max_form_body_size: 1048576,
+            header_timeout: 10,
+            body_timeout: 30,
+            keep_alive_timeout: 60,
+        }
+    }
+}
+
+/*Certificates issued & renewed by an ACME CA with http-01 challenges, empty domains disables it*/
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct AcmeConfig {
+    pub directory_url : String,
+    pub contact_email : String,
+    pub domains : Vec<String>,
+    // account key, certificate & key files
+    pub storage_dir : String,
+    // extra trusted root for the directory url, like the Pebble test CA
+    pub ca_file : String,
+    pub renew_before_days : u64
+}
+
+impl AcmeConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.domains.is_empty()
+    }
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        AcmeConfig {
+            directory_url: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+            contact_email: String::new(),
+            domains: Vec::new(),
+            storage_dir: "acme".to_string(),
+            ca_file: String::new(),
+            renew_before_days: 30,
+        }
+    }
+}
+
+/*Prometheus `/{base_url}/metrics` route, not served unless enabled*/
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled : bool
+}
+
+/*Per client ip limits, 0 disables a limit*/
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub requests_per_second : f64,
+    pub burst : u32,
+    pub max_connections : usize,
+    pub max_connections_per_ip : usize,
+    pub max_bytes_per_ip_per_hour : u64
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            requests_per_second: 0.0,
+            burst: 100,
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            max_bytes_per_ip_per_hour: 0,
+        }
+    }
+}
+
+/*Speedtest server list entry, serialized in librespeed frontend format*/
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SpeedtestServer {
+    #[serde(default)]
+    pub id : u32,
+    pub name : String,
+    pub server : String,
+    #[serde(default, rename(serialize = "dlURL"))]
+    pub dl_url : String,
+    #[serde(default, rename(serialize = "ulURL"))]
+    pub ul_url : String,
+    #[serde(default, rename(serialize = "pingURL"))]
+    pub ping_url : String,
+    #[serde(default, rename(serialize = "getIpURL"))]
+    pub get_ip_url : String,
+    #[serde(rename(serialize = "sponsorName"), skip_serializing_if = "Option::is_none")]
+    pub sponsor_name : Option<String>,
+    #[serde(rename(serialize = "sponsorURL"), skip_serializing_if = "Option::is_none")]
+    pub sponsor_url : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location : Option<String>
+}
+
+fn default_trusted_proxies() -> Vec<String> {
+    vec!["127.0.0.0/8".to_string(),"::1/128".to_string()]
+}
+
+fn default_client_ip_header() -> String {
+    "x-forwarded-for".to_string()
+}
+
+fn default_garbage_mode() -> String {
+    "random".to_string()
+}
+
+fn default_garbage_chunk_size() -> usize {
+    524288 //512 Kilobytes
+}
+
+fn default_max_download_bytes() -> u64 {
+    1073741824 //1 Gigabyte
+}
+
+fn default_max_download_duration() -> u64 {
+    60
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    30
 }
 
 impl Default for ServerConfig {
@@ -78,6 +328,23 @@ impl Default for ServerConfig {
             enable_tls: false,
             tls_cert_file: "".to_string(),
             tls_key_file: "".to_string(),
+            proxy_protocol: false,
+            trusted_proxies: default_trusted_proxies(),
+            client_ip_header: default_client_ip_header(),
+            garbage_mode: default_garbage_mode(),
+            garbage_chunk_size: default_garbage_chunk_size(),
+            max_download_bytes: default_max_download_bytes(),
+            max_download_duration: default_max_download_duration(),
+            shutdown_grace_period: default_shutdown_grace_period(),
+            tls_certificates: Vec::new(),
+            listeners: Vec::new(),
+            servers: Vec::new(),
+            cors: CorsConfig::default(),
+            limits: LimitsConfig::default(),
+            client_auth: ClientAuthConfig::default(),
+            acme: AcmeConfig::default(),
+            request_limits: RequestLimitsConfig::default(),
+            metrics: MetricsConfig::default(),
         }
     }
 }
@@ -88,17 +355,20 @@ pub fn init_runtime () -> std::io::Result<Runtime> {
         Builder::new_multi_thread()
             .thread_name("librespeed-rs")
             .enable_io()
+            .enable_time()
             .build()
     } else {
         let worker_threads = worker_threads.as_u64().unwrap_or(1) as usize;
         match worker_threads {
             0 | 1 => Builder::new_current_thread()
                 .enable_io()
+                .enable_time()
                 .build(),
             _ => Builder::new_multi_thread()
                 .thread_name("librespeed-rs")
                 .worker_threads(worker_threads)
                 .enable_io()
+                .enable_time()
                 .build(),
         }
     }
@@ -179,7 +449,7 @@ fn validate_base_url_path(base_url : &str) -> String {
     }
 }
 
-fn generate_routes(base_url : &str) {
+fn generate_routes(base_url : &str,metrics : bool) {
     let mut routes = HashMap::new();
     routes.insert(format!("{base_url}/empty"),"empty");
     routes.insert(format!("{base_url}/garbage"),"garbage");
@@ -187,12 +457,20 @@ fn generate_routes(base_url : &str) {
     routes.insert(format!("{base_url}/results"),"results");
     routes.insert(format!("{base_url}/results/telemetry"),"results/telemetry");
     routes.insert(format!("{base_url}/stats"),"stats");
+    routes.insert(format!("{base_url}/servers.json"),"servers.json");
+    routes.insert(format!("{base_url}/ws"),"ws");
+    if metrics {
+        routes.insert(format!("{base_url}/metrics"),"metrics");
+    }
     ROUTES.get_or_init(|| routes);
 }
 
 fn initialize (mut config: ServerConfig,cmd : Cmd) -> std::io::Result<()> {
     //server config
     config.base_url = validate_base_url_path(&config.base_url);
+    if !config.listeners.is_empty() && (cmd.bind_address.is_some() || cmd.listen_port.is_some() || cmd.enable_tls.is_some()) {
+        warn!("[[listeners]] are configured, command line bind address, listen port & enable tls are ignored");
+    }
     config.bind_address.set_if_some(cmd.bind_address);
     config.listen_port.set_if_some(cmd.listen_port);
     config.base_url.set_if_some(cmd.base_url);
@@ -210,8 +488,22 @@ fn initialize (mut config: ServerConfig,cmd : Cmd) -> std::io::Result<()> {
     config.enable_tls.set_if_some(cmd.enable_tls);
     config.tls_cert_file.set_if_some(cmd.tls_cert_file);
     config.tls_key_file.set_if_some(cmd.tls_key_file);
-    generate_routes(&config.base_url);
+    if config.acme.is_enabled() {
+        //tls listeners without their own certificate use the issued one
+        config.tls_cert_file = acme::cert_file(&config.acme.storage_dir);
+        config.tls_key_file = acme::key_file(&config.acme.storage_dir);
+    }
+    init_listeners(&mut config)?;
+    generate_routes(&config.base_url,config.metrics.enabled);
+    fill_servers_defaults(&mut config.servers,&config.base_url);
+    if !config.servers.is_empty() {
+        info!("Config server list with {} servers.",config.servers.len())
+    }
     if !config.assets_path.is_empty() {
+        //resolved once, served files are checked to stay inside it
+        if let Ok(assets_root) = Path::new(&config.assets_path).canonicalize() {
+            config.assets_path = assets_root.to_string_lossy().to_string();
+        }
         if check_assets_path(&config.assets_path) {
             info!("Config assets directory successfully.")
         } else {
@@ -220,28 +512,112 @@ fn initialize (mut config: ServerConfig,cmd : Cmd) -> std::io::Result<()> {
     } else {
         info!("Config default assets directory.")
     }
+    init_trusted_proxies(&config.trusted_proxies,&config.client_ip_header)?;
+    if GarbageMode::from_config(&config.garbage_mode).is_none() {
+        return Err(Error::other(format!("Invalid garbage_mode : {}, use zeros, random or stream",config.garbage_mode)))
+    }
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&config.garbage_chunk_size) {
+        return Err(Error::other(format!("Invalid garbage_chunk_size : {}, must be between {} and {} bytes",config.garbage_chunk_size,MIN_CHUNK_SIZE,MAX_CHUNK_SIZE)))
+    }
+    if config.max_download_bytes == 0 || config.max_download_duration == 0 {
+        return Err(Error::other("max_download_bytes and max_download_duration must be greater than 0"))
+    }
+    if config.cors.allow_credentials && config.cors.allowed_origins.iter().any(|origin| origin == "*") {
+        return Err(Error::other("cors.allow_credentials can't be used with the \"*\" origin, list the allowed origins instead"))
+    }
+    if config.limits.requests_per_second > 0.0 && config.limits.burst == 0 {
+        return Err(Error::other("limits.burst must be greater than 0 when requests_per_second is set"))
+    }
+    if let Some(route) = config.client_auth.routes.iter().find(|route| !ROUTES.get().unwrap().values().any(|known| known == route)) {
+        return Err(Error::other(format!("Invalid client_auth.routes entry : {}",route)))
+    }
+    let request_limits = &config.request_limits;
+    if request_limits.max_request_line == 0 || request_limits.max_header_count == 0 || request_limits.max_header_size == 0 || request_limits.max_form_body_size == 0 {
+        return Err(Error::other("request_limits sizes must be greater than 0"))
+    }
+    if config.acme.is_enabled() {
+        if !config.listeners.iter().any(|listener| listener.tls) {
+            return Err(Error::other("acme needs enable_tls or a tls listener"))
+        }
+        if config.acme.renew_before_days == 0 {
+            return Err(Error::other("acme.renew_before_days must be greater than 0"))
+        }
+        acme::init_acme(&config.acme)?;
+    }
+    info!("Config garbage mode : {}",config.garbage_mode);
     SERVER_CONFIG.get_or_init(|| config);
     //garbage data
-    let chunk_len = format!("{:X}\r\n", CHUNK_SIZE);
-    let mut garbage = Vec::new();
-    garbage.extend(chunk_len.as_bytes());
-    garbage.extend(vec![0; CHUNK_SIZE]);
-    garbage.extend(b"\r\n");
-    GARBAGE_DATA.get_or_init(|| garbage);
+    init_garbage();
     //font for result image
     FONT.get_or_init(|| FontRef::try_from_slice(include_bytes!("../../assets/open-sans.ttf")).unwrap());
     Ok(())
 }
 
+fn init_listeners(config : &mut ServerConfig) -> std::io::Result<()> {
+    if config.listeners.is_empty() {
+        config.listeners.push(ListenerConfig {
+            address: config.bind_address.clone(),
+            port: config.listen_port,
+            tls: config.enable_tls,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            proxy_protocol: config.proxy_protocol,
+            redirect_to_https: false,
+        });
+    }
+    for listener in config.listeners.iter_mut() {
+        if listener.tls && listener.redirect_to_https {
+            return Err(Error::other(format!("Listener {}:{} can't use tls and redirect_to_https together",listener.address,listener.port)))
+        }
+        if listener.tls_cert_file.is_empty() {
+            listener.tls_cert_file = config.tls_cert_file.clone();
+        }
+        if listener.tls_key_file.is_empty() {
+            listener.tls_key_file = config.tls_key_file.clone();
+        }
+    }
+    Ok(())
+}
+
+fn fill_servers_defaults(servers : &mut [SpeedtestServer],base_url : &str) {
+    let base_url = relative_base_url(base_url);
+    for (index,server) in servers.iter_mut().enumerate() {
+        if server.id == 0 {
+            server.id = index as u32 + 1;
+        }
+        if server.dl_url.is_empty() {
+            server.dl_url = format!("{base_url}garbage");
+        }
+        if server.ul_url.is_empty() {
+            server.ul_url = format!("{base_url}empty");
+        }
+        if server.ping_url.is_empty() {
+            server.ping_url = format!("{base_url}empty");
+        }
+        if server.get_ip_url.is_empty() {
+            server.get_ip_url = format!("{base_url}getIP");
+        }
+    }
+}
+
+// base url relative to server root, `/backend` -> `backend/`
+pub fn relative_base_url(base_url : &str) -> String {
+    if base_url.is_empty() {
+        "".to_string()
+    } else {
+        format!("{}/",&base_url[1..])
+    }
+}
+
 fn check_assets_path (dir : &str) -> bool {
     let index_file = format!("{}/index.html",dir);
     Path::new(&index_file).exists()
 }
 
 /*Static Values*/
-const CHUNK_SIZE : usize = 524288; //512 Kilobytes x2
+pub const MIN_CHUNK_SIZE : usize = 4096;
+pub const MAX_CHUNK_SIZE : usize = 16777216; //16 Megabytes
 pub static ROUTES: OnceLock<HashMap<String,&str>> = OnceLock::new();
-pub static GARBAGE_DATA: OnceLock<Vec<u8>> = OnceLock::new();
 pub static SERVER_CONFIG: OnceLock<ServerConfig> = OnceLock::new();
 pub static FONT: OnceLock<FontRef> = OnceLock::new();
 pub static DEF_ASSETS : Dir = include_dir!("assets");
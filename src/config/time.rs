@@ -1,5 +1,5 @@
 use std::time::{SystemTime, UNIX_EPOCH};
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, Utc};
 use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext};
 
 pub fn convert_time_local (time : i64) -> String {
@@ -21,7 +21,6 @@ pub fn current_formatted_time() -> String {
     Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string()
 }
 
-#[allow(dead_code)]
 pub fn convert_time_utc (time : i64) -> String {
     let dt = DateTime::from_timestamp_millis(time).unwrap();
     dt.format("%Y-%m-%d %H:%M:%S").to_string()
@@ -35,4 +34,15 @@ pub fn get_current_millis () -> i64 {
     let in_ms = since_the_epoch.as_secs() * 1000 +
         since_the_epoch.subsec_nanos() as u64 / 1_000_000;
     in_ms as i64
-}
\ No newline at end of file
+}
+
+// IMF-fixdate used by Last-Modified & If-Modified-Since headers
+pub fn format_http_date (time : SystemTime) -> String {
+    let dt : DateTime<Utc> = DateTime::from(time);
+    dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+pub fn parse_http_date (date : &str) -> Option<i64> {
+    let date = date.trim().replace("GMT","+0000");
+    DateTime::parse_from_rfc2822(&date).ok().map(|dt| dt.timestamp())
+}
@@ -1,5 +1,5 @@
 use std::io::Error;
-use mysql::{Conn, Row};
+use mysql::{Conn, Params, Row, Value};
 use mysql::prelude::Queryable;
 use crate::database::{Database, DBRawToStruct};
 use crate::results::TelemetryData;
@@ -29,10 +29,15 @@ pub fn init (username : &Option<String>,password : &Option<String>,host_name : &
                                     jitter text,\
                                     log text,\
                                     uuid text,\
-                                    `timestamp` bigint\
+                                    `timestamp` bigint,\
+                                    server_download text,\
+                                    server_upload text\
                                 )",());
                 match create_table {
                     Ok(_) => {
+                        //tables created before server measurements, fails when columns already exist
+                        let _ = connection.exec_drop("ALTER TABLE speedtest_users ADD COLUMN server_download text",());
+                        let _ = connection.exec_drop("ALTER TABLE speedtest_users ADD COLUMN server_upload text",());
                         drop(connection);
                         Ok(conn_url)
                     }
@@ -63,6 +68,8 @@ impl DBRawToStruct<Error> for Row {
             log: self.get(10).unwrap_or("".to_string()),
             uuid: self.get(11).unwrap_or("".to_string()),
             timestamp: self.get(12).unwrap_or(0),
+            server_download: self.get::<Option<String>,_>(13).flatten().unwrap_or_default(),
+            server_upload: self.get::<Option<String>,_>(14).flatten().unwrap_or_default(),
         })
     }
 }
@@ -72,10 +79,10 @@ impl Database for MySql {
     fn insert(&mut self,data : TelemetryData) -> std::io::Result<()> {
         let mut connection = Conn::new(self.connection.as_str()).unwrap();
         let insert = connection.exec_drop("INSERT INTO speedtest_users \
-                                                (ip_address,isp_info,extra,user_agent,lang,download,upload,ping,jitter,log,uuid,timestamp) \
+                                                (ip_address,isp_info,extra,user_agent,lang,download,upload,ping,jitter,log,uuid,timestamp,server_download,server_upload) \
                                                 VALUES \
-                                                (?,?,?,?,?,?,?,?,?,?,?,?)",
-                                          (&data.ip_address, &data.isp_info, &data.extra, &data.user_agent, &data.lang, &data.download, &data.upload, &data.ping, &data.jitter, &data.log, &data.uuid, &data.timestamp));
+                                                (?,?,?,?,?,?,?,?,?,?,?,?,?,?)",
+                                          Params::Positional(vec![Value::from(&data.ip_address), Value::from(&data.isp_info), Value::from(&data.extra), Value::from(&data.user_agent), Value::from(&data.lang), Value::from(&data.download), Value::from(&data.upload), Value::from(&data.ping), Value::from(&data.jitter), Value::from(&data.log), Value::from(&data.uuid), Value::from(data.timestamp), Value::from(&data.server_download), Value::from(&data.server_upload)]));
         drop(data);
         drop(connection);
         match insert {
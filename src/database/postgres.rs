@@ -32,8 +32,13 @@ pub fn init (username : &Option<String>,password : &Option<String>,host_name : &
                             jitter text,\
                             log text,\
                             uuid text,\
-                            \"timestamp\" bigint\
-                            )",&[])
+                            \"timestamp\" bigint,\
+                            server_download text,\
+                            server_upload text\
+                            )",&[])?;
+                        //tables created before server measurements
+                        client.execute("ALTER TABLE speedtest_users ADD COLUMN IF NOT EXISTS server_download text",&[])?;
+                        client.execute("ALTER TABLE speedtest_users ADD COLUMN IF NOT EXISTS server_upload text",&[])
                     });
                     match create_table {
                         Ok(_) => {
@@ -67,6 +72,8 @@ impl DBRawToStruct<Error> for Row {
             log: self.get(10),
             uuid: self.get(11),
             timestamp: self.get(12),
+            server_download: self.get::<_,Option<String>>(13).unwrap_or_default(),
+            server_upload: self.get::<_,Option<String>>(14).unwrap_or_default(),
         })
     }
 }
@@ -75,10 +82,10 @@ impl Database for Postgres {
     fn insert(&mut self,data : TelemetryData) -> std::io::Result<()> {
         let insert = block_in_place(|| {
             self.connection.execute("INSERT INTO speedtest_users \
-                                                (ip_address,isp_info,extra,user_agent,lang,download,upload,ping,jitter,log,uuid,timestamp) \
+                                                (ip_address,isp_info,extra,user_agent,lang,download,upload,ping,jitter,log,uuid,timestamp,server_download,server_upload) \
                                                 VALUES \
-                                                ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12)",
-                                    &[&data.ip_address, &data.isp_info, &data.extra, &data.user_agent, &data.lang, &data.download, &data.upload, &data.ping, &data.jitter, &data.log, &data.uuid, &data.timestamp])
+                                                ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14)",
+                                    &[&data.ip_address, &data.isp_info, &data.extra, &data.user_agent, &data.lang, &data.download, &data.upload, &data.ping, &data.jitter, &data.log, &data.uuid, &data.timestamp, &data.server_download, &data.server_upload])
         });
         drop(data);
         match insert {
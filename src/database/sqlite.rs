@@ -30,12 +30,17 @@ pub fn init (database_file : &Option<String>) -> std::io::Result<Connection> {
                                     jitter TEXT,\
                                     log TEXT,\
                                     uuid TEXT,\
-                                    timestamp INTEGER\
+                                    timestamp INTEGER,\
+                                    server_download TEXT,\
+                                    server_upload TEXT\
                                 )",
                         (),
                     );
                     match create_table {
                         Ok(_) => {
+                            //tables created before server measurements, fails when columns already exist
+                            let _ = connection.execute("ALTER TABLE speedtest_users ADD COLUMN server_download TEXT",());
+                            let _ = connection.execute("ALTER TABLE speedtest_users ADD COLUMN server_upload TEXT",());
                             Ok(connection)
                         }
                         Err(e) => {
@@ -66,6 +71,8 @@ impl DBRawToStruct<rusqlite::Error> for Row<'_> {
             log: self.get(10)?,
             uuid: self.get(11)?,
             timestamp: self.get(12)?,
+            server_download: self.get::<_,Option<String>>(13)?.unwrap_or_default(),
+            server_upload: self.get::<_,Option<String>>(14)?.unwrap_or_default(),
         })
     }
 }
@@ -73,10 +80,10 @@ impl DBRawToStruct<rusqlite::Error> for Row<'_> {
 impl Database for SQLite {
     fn insert(&mut self, data: TelemetryData) -> std::io::Result<()> {
         let insert = self.connection.execute("INSERT INTO speedtest_users \
-                                                (ip_address,isp_info,extra,user_agent,lang,download,upload,ping,jitter,log,uuid,timestamp) \
+                                                (ip_address,isp_info,extra,user_agent,lang,download,upload,ping,jitter,log,uuid,timestamp,server_download,server_upload) \
                                                 VALUES \
-                                                (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12)",
-                                             (&data.ip_address, &data.isp_info, &data.extra, &data.user_agent, &data.lang, &data.download, &data.upload, &data.ping, &data.jitter, &data.log, &data.uuid, &data.timestamp));
+                                                (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14)",
+                                             (&data.ip_address, &data.isp_info, &data.extra, &data.user_agent, &data.lang, &data.download, &data.upload, &data.ping, &data.jitter, &data.log, &data.uuid, &data.timestamp, &data.server_download, &data.server_upload));
         drop(data);
         match insert {
             Ok(_) => {
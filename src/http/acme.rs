@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use log::{error, info};
+use rcgen::{CertificateParams, KeyPair};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use x509_parser::parse_x509_certificate;
+use crate::config::{AcmeConfig, SERVER_CONFIG};
+use crate::http::http_client::{ClientResponse, HttpClient};
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::http::tls::{certificate_info, reload_certificates};
+
+/*ACME (RFC 8555) certificates, http-01 challenges are answered by the existing listeners*/
+
+const ACCOUNT_KEY_FILE : &str = "account.key";
+const CERT_FILE : &str = "cert.pem";
+const KEY_FILE : &str = "key.pem";
+const CHALLENGE_PATH : &str = "/.well-known/acme-challenge/";
+const POLL_INTERVAL : Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS : usize = 60;
+const BAD_NONCE_RETRIES : usize = 3;
+const RENEW_CHECK_INTERVAL : Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL : Duration = Duration::from_secs(3600);
+const CONNECT_TIMEOUT : Duration = Duration::from_secs(10);
+const RESPONSE_TIMEOUT : Duration = Duration::from_secs(30);
+
+// token -> key authorization of pending http-01 challenges
+static CHALLENGES: OnceLock<Mutex<HashMap<String,String>>> = OnceLock::new();
+
+fn acme_config() -> Option<&'static AcmeConfig> {
+    SERVER_CONFIG.get().map(|config| &config.acme).filter(|acme| acme.is_enabled())
+}
+
+pub fn cert_file(storage_dir : &str) -> String {
+    Path::new(storage_dir).join(CERT_FILE).to_string_lossy().to_string()
+}
+
+pub fn key_file(storage_dir : &str) -> String {
+    Path::new(storage_dir).join(KEY_FILE).to_string_lossy().to_string()
+}
+
+// private files are only readable by the owner on unix, the mode applies on creation
+fn create_file(path : &str,content : &[u8],private : bool) -> std::io::Result<()> {
+    let _ = std::fs::remove_file(path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+// write through a temporary file, the certificate reloader never sees a partial file
+fn write_file(path : &str,content : &[u8],private : bool) -> std::io::Result<()> {
+    let temp_path = format!("{path}.tmp");
+    create_file(&temp_path,content,private)?;
+    std::fs::rename(&temp_path,path)
+}
+
+/*both files are complete before either is replaced, the certificate goes last
+and the reloader keeps the current pair while the new key does not match it*/
+fn write_certificate(storage_dir : &str,key_pem : &[u8],cert_pem : &[u8]) -> std::io::Result<()> {
+    let key_path = key_file(storage_dir);
+    let cert_path = cert_file(storage_dir);
+    let key_temp = format!("{key_path}.tmp");
+    let cert_temp = format!("{cert_path}.tmp");
+    create_file(&key_temp,key_pem,true)?;
+    create_file(&cert_temp,cert_pem,false)?;
+    std::fs::rename(&key_temp,&key_path)?;
+    std::fs::rename(&cert_temp,&cert_path)
+}
+
+/*storage directory & a self signed certificate, tls listeners start with it until the first one is issued*/
+pub fn init_acme(acme : &AcmeConfig) -> std::io::Result<()> {
+    std::fs::create_dir_all(&acme.storage_dir)?;
+    if Path::new(&cert_file(&acme.storage_dir)).exists() && Path::new(&key_file(&acme.storage_dir)).exists() {
+        return Ok(())
+    }
+    let self_signed = rcgen::generate_simple_self_signed(acme.domains.clone()).map_err(Error::other)?;
+    write_certificate(&acme.storage_dir,self_signed.key_pair.serialize_pem().as_bytes(),self_signed.cert.pem().as_bytes())?;
+    info!("ACME temporary self signed certificate created in {}",acme.storage_dir);
+    Ok(())
+}
+
+/*answer of an http-01 validation request, None for any other path*/
+pub fn challenge_response(request : &Request) -> Option<Response> {
+    let token = request.path.strip_prefix(CHALLENGE_PATH)?;
+    acme_config()?;
+    let key_authorization = CHALLENGES.get()
+        .and_then(|challenges| challenges.lock().unwrap().get(token).cloned());
+    match key_authorization {
+        Some(key_authorization) => Some(Response::res_200(&key_authorization)),
+        None => Some(Response::res_404())
+    }
+}
+
+// missing, self signed, expiring or not covering the configured domains
+fn needs_renewal(acme : &AcmeConfig) -> bool {
+    let Ok(pem) = std::fs::read(cert_file(&acme.storage_dir)) else {
+        return true
+    };
+    let Some(Ok(cert)) = rustls_pemfile::certs(&mut pem.as_slice()).next() else {
+        return true
+    };
+    let Ok((_,parsed)) = parse_x509_certificate(cert.as_ref()) else {
+        return true
+    };
+    if parsed.issuer() == parsed.subject() {
+        return true
+    }
+    let Some((names,not_after)) = certificate_info(&cert) else {
+        return true
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0);
+    let covers_domains = acme.domains.iter().all(|domain| names.contains(&domain.to_ascii_lowercase()));
+    !covers_domains || not_after - now <= (acme.renew_before_days * 24 * 3600) as i64
+}
+
+/*issue on startup when needed, then check twice a day for renewal*/
+pub fn spawn_acme_manager() {
+    let Some(acme) = acme_config() else {
+        return
+    };
+    tokio::spawn(async move {
+        loop {
+            let wait = if needs_renewal(acme) {
+                info!("ACME requesting certificate for {}",acme.domains.join(", "));
+                match issue_certificate(acme).await {
+                    Ok(()) => {
+                        info!("ACME certificate issued for {}",acme.domains.join(", "));
+                        reload_certificates();
+                        RENEW_CHECK_INTERVAL
+                    }
+                    Err(e) => {
+                        error!("ACME certificate request failed, retrying in {} minutes : {e}",RETRY_INTERVAL.as_secs() / 60);
+                        RETRY_INTERVAL
+                    }
+                }
+            } else {
+                RENEW_CHECK_INTERVAL
+            };
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+async fn issue_certificate(acme : &AcmeConfig) -> std::io::Result<()> {
+    let mut client = AcmeClient::new(acme).await?;
+    client.register_account(acme).await?;
+    let identifiers = acme.domains.iter().map(|domain| json!({"type": "dns", "value": domain})).collect::<Vec<Value>>();
+    let response = client.post(&client.directory.new_order.clone(),Some(&json!({"identifiers": identifiers}))).await?;
+    let order_url = response.headers.get("Location").cloned().ok_or_else(|| Error::other("ACME order without location"))?;
+    let order = parse_json(&response)?;
+    let authorizations = order["authorizations"].as_array().cloned().unwrap_or_default();
+    for authorization in authorizations {
+        let authorization_url = authorization.as_str().ok_or_else(|| Error::other("ACME invalid authorization url"))?;
+        client.authorize(authorization_url).await?;
+    }
+    let order = client.poll(&order_url,&["ready","valid"]).await?;
+    // new key for every certificate, the account key only signs requests
+    let key_pair = KeyPair::generate().map_err(Error::other)?;
+    let csr = CertificateParams::new(acme.domains.clone()).and_then(|params| params.serialize_request(&key_pair)).map_err(Error::other)?;
+    if order["status"] == "ready" {
+        let finalize_url = order["finalize"].as_str().ok_or_else(|| Error::other("ACME order without finalize url"))?;
+        client.post(finalize_url,Some(&json!({"csr": URL_SAFE_NO_PAD.encode(csr.der())}))).await?;
+    }
+    let order = client.poll(&order_url,&["valid"]).await?;
+    let certificate_url = order["certificate"].as_str().ok_or_else(|| Error::other("ACME order without certificate url"))?;
+    let certificate = client.post(certificate_url,None).await?.body;
+    if rustls_pemfile::certs(&mut certificate.as_slice()).next().is_none() {
+        return Err(Error::other("ACME certificate response is not a PEM chain"))
+    }
+    write_certificate(&acme.storage_dir,key_pair.serialize_pem().as_bytes(),&certificate)
+}
+
+fn parse_json(response : &ClientResponse) -> std::io::Result<Value> {
+    serde_json::from_slice(&response.body).map_err(|e| Error::other(format!("ACME invalid json response : {e}")))
+}
+
+struct Directory {
+    new_nonce : String,
+    new_account : String,
+    new_order : String
+}
+
+/*account key pair signing JWS requests, ES256*/
+struct Account {
+    key_pair : EcdsaKeyPair,
+    jwk : Value,
+    thumbprint : String,
+    kid : Option<String>
+}
+
+impl Account {
+    fn load_or_create(storage_dir : &str) -> std::io::Result<Self> {
+        let path = Path::new(storage_dir).join(ACCOUNT_KEY_FILE).to_string_lossy().to_string();
+        let key_pair = match std::fs::read_to_string(&path) {
+            Ok(pem) => KeyPair::from_pem(&pem).map_err(|e| Error::other(format!("Invalid ACME account key {path} : {e}")))?,
+            Err(_) => {
+                let key_pair = KeyPair::generate().map_err(Error::other)?;
+                write_file(&path,key_pair.serialize_pem().as_bytes(),true)?;
+                info!("ACME account key created in {path}");
+                key_pair
+            }
+        };
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING,&key_pair.serialize_der(),&SystemRandom::new())
+            .map_err(|_| Error::other(format!("ACME account key {path} is not a P-256 key")))?;
+        // uncompressed point : 0x04 | x | y
+        let public_key = key_pair.public_key().as_ref();
+        let x = URL_SAFE_NO_PAD.encode(&public_key[1..33]);
+        let y = URL_SAFE_NO_PAD.encode(&public_key[33..65]);
+        // RFC 7638 thumbprint, members in lexicographic order without whitespace
+        let thumbprint = URL_SAFE_NO_PAD.encode(Sha256::digest(format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#)));
+        Ok(Account {
+            key_pair,
+            jwk: json!({"crv": "P-256", "kty": "EC", "x": x, "y": y}),
+            thumbprint,
+            kid: None
+        })
+    }
+}
+
+struct AcmeClient {
+    ca_file : Option<String>,
+    directory : Directory,
+    account : Account,
+    nonce : Option<String>
+}
+
+impl AcmeClient {
+    async fn new(acme : &AcmeConfig) -> std::io::Result<Self> {
+        let ca_file = if acme.ca_file.is_empty() { None } else { Some(acme.ca_file.clone()) };
+        let response = Self::request(ca_file.as_deref(),&acme.directory_url,"GET",&[],&[]).await?;
+        let directory = parse_json(&response)?;
+        let url = |name : &str| directory[name].as_str().map(str::to_string).ok_or_else(|| Error::other(format!("ACME directory without {name}")));
+        Ok(AcmeClient {
+            directory: Directory {
+                new_nonce: url("newNonce")?,
+                new_account: url("newAccount")?,
+                new_order: url("newOrder")?,
+            },
+            account: Account::load_or_create(&acme.storage_dir)?,
+            ca_file,
+            nonce: None
+        })
+    }
+
+    // a stalled ACME server fails the attempt instead of blocking renewals
+    async fn request(ca_file : Option<&str>,url : &str,method : &str,headers : &[(&str,&str)],body : &[u8]) -> std::io::Result<ClientResponse> {
+        let mut client = tokio::time::timeout(CONNECT_TIMEOUT,HttpClient::open_with_ca(url,ca_file)).await
+            .map_err(|_| Error::new(ErrorKind::TimedOut,format!("ACME connect timeout on {url}")))??;
+        tokio::time::timeout(RESPONSE_TIMEOUT,client.send_request(method,headers,body)).await
+            .map_err(|_| Error::new(ErrorKind::TimedOut,format!("ACME response timeout on {url}")))?
+    }
+
+    async fn nonce(&mut self) -> std::io::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce)
+        }
+        let response = Self::request(self.ca_file.as_deref(),&self.directory.new_nonce,"HEAD",&[],&[]).await?;
+        response.headers.get("Replay-Nonce").cloned().ok_or_else(|| Error::other("ACME server sent no nonce"))
+    }
+
+    // signed POST, a None payload is a POST-as-GET
+    async fn post(&mut self,url : &str,payload : Option<&Value>) -> std::io::Result<ClientResponse> {
+        let payload = payload.map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string())).unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            let mut protected = json!({"alg": "ES256", "nonce": self.nonce().await?, "url": url});
+            match &self.account.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.account.jwk.clone()
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self.account.key_pair.sign(&SystemRandom::new(),format!("{protected}.{payload}").as_bytes())
+                .map_err(|_| Error::other("ACME request signing failed"))?;
+            let body = json!({"protected": protected, "payload": payload, "signature": URL_SAFE_NO_PAD.encode(signature.as_ref())});
+            let response = Self::request(self.ca_file.as_deref(),url,"POST",&[("Content-Type","application/jose+json")],body.to_string().as_bytes()).await?;
+            self.nonce = response.headers.get("Replay-Nonce").cloned();
+            if response.status < 400 {
+                return Ok(response)
+            }
+            let problem = parse_json(&response).unwrap_or_default();
+            attempt += 1;
+            if problem["type"] != "urn:ietf:params:acme:error:badNonce" || attempt >= BAD_NONCE_RETRIES {
+                return Err(Error::other(format!("ACME error {} on {url} : {}",response.status,problem["detail"].as_str().unwrap_or_default())))
+            }
+        }
+    }
+
+    async fn register_account(&mut self,acme : &AcmeConfig) -> std::io::Result<()> {
+        let mut payload = json!({"termsOfServiceAgreed": true});
+        if !acme.contact_email.is_empty() {
+            payload["contact"] = json!([format!("mailto:{}",acme.contact_email)]);
+        }
+        let response = self.post(&self.directory.new_account.clone(),Some(&payload)).await?;
+        self.account.kid = Some(response.headers.get("Location").cloned().ok_or_else(|| Error::other("ACME account without location"))?);
+        Ok(())
+    }
+
+    async fn authorize(&mut self,authorization_url : &str) -> std::io::Result<()> {
+        let authorization = parse_json(&self.post(authorization_url,None).await?)?;
+        if authorization["status"] == "valid" {
+            return Ok(())
+        }
+        let domain = authorization["identifier"]["value"].as_str().unwrap_or_default().to_string();
+        let challenge = authorization["challenges"].as_array()
+            .and_then(|challenges| challenges.iter().find(|challenge| challenge["type"] == "http-01"))
+            .ok_or_else(|| Error::other(format!("ACME no http-01 challenge offered for {domain}")))?;
+        let (Some(token),Some(challenge_url)) = (challenge["token"].as_str(),challenge["url"].as_str()) else {
+            return Err(Error::other(format!("ACME invalid http-01 challenge for {domain}")))
+        };
+        let challenges = CHALLENGES.get_or_init(|| Mutex::new(HashMap::new()));
+        challenges.lock().unwrap().insert(token.to_string(),format!("{token}.{}",self.account.thumbprint));
+        let result = async {
+            self.post(challenge_url,Some(&json!({}))).await?;
+            self.poll(authorization_url,&["valid"]).await
+        }.await;
+        challenges.lock().unwrap().remove(token);
+        result.map(|_| ()).map_err(|e| Error::other(format!("ACME validation of {domain} failed : {e}")))
+    }
+
+    // POST-as-GET until the object reaches one of the expected states
+    async fn poll(&mut self,url : &str,expected : &[&str]) -> std::io::Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object = parse_json(&self.post(url,None).await?)?;
+            let status = object["status"].as_str().unwrap_or_default();
+            if expected.contains(&status) {
+                return Ok(object)
+            }
+            if status == "invalid" {
+                let detail = object["error"]["detail"].as_str()
+                    .or_else(|| object["challenges"].as_array()?.iter().find_map(|challenge| challenge["error"]["detail"].as_str()))
+                    .unwrap_or("invalid");
+                return Err(Error::other(detail.to_string()))
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(Error::other(format!("ACME timeout waiting for {url}")))
+    }
+}
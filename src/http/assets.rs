@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+use http::StatusCode;
+use tokio::io::AsyncReadExt;
+use crate::config::{DEF_ASSETS, SERVER_CONFIG};
+use crate::config::time::{format_http_date, parse_http_date};
+use crate::http::compression::{compress, is_compressible, negotiate, precompressed_asset, Encoding};
+use crate::http::generate_server_endpoint;
+use crate::http::params::percent_decode;
+use crate::http::request::Request;
+use crate::http::response::{Body, Response, ResponseBuilder};
+
+/*Static frontend assets, embedded in binary or served from assets_path*/
+
+// larger files from assets_path are served without encoding
+const MAX_ENCODED_FILE_SIZE : u64 = 4 * 1024 * 1024;
+// encoded assets_path files kept in memory, emptied when a new entry would go past it
+const MAX_ENCODED_CACHE_SIZE : usize = 64 * 1024 * 1024;
+
+static ENCODED_FILES: OnceLock<Mutex<EncodedFiles>> = OnceLock::new();
+
+/*assets_path files are compressed once per version & encoding, not on every request*/
+#[derive(Default)]
+struct EncodedFiles {
+    // (canonical path, encoding) -> (identity etag of the encoded version, encoded content)
+    files : HashMap<(String,Encoding),(String,Vec<u8>)>,
+    size : usize
+}
+
+impl EncodedFiles {
+    fn get(&self,path : &str,encoding : Encoding,etag : &str) -> Option<Vec<u8>> {
+        self.files.get(&(path.to_string(),encoding))
+            .filter(|(cached_etag,_)| cached_etag == etag)
+            .map(|(_,content)| content.clone())
+    }
+
+    fn insert(&mut self,path : &str,encoding : Encoding,etag : &str,content : &[u8]) {
+        if let Some((_,previous)) = self.files.remove(&(path.to_string(),encoding)) {
+            self.size -= previous.len();
+        }
+        if self.size + content.len() > MAX_ENCODED_CACHE_SIZE {
+            self.files.clear();
+            self.size = 0;
+        }
+        self.size += content.len();
+        self.files.insert((path.to_string(),encoding),(etag.to_string(),content.to_vec()));
+    }
+}
+
+// cached encoded content of a file version, the file is only read & compressed on a miss
+fn encoded_file(path : &str,encoding : Encoding,etag : &str,file : &mut File) -> Option<Vec<u8>> {
+    let cache = ENCODED_FILES.get_or_init(|| Mutex::new(EncodedFiles::default()));
+    if let Some(encoded) = cache.lock().unwrap().get(path,encoding,etag) {
+        return Some(encoded)
+    }
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).ok()?;
+    let encoded = compress(encoding,&content)?;
+    cache.lock().unwrap().insert(path,encoding,etag,&encoded);
+    Some(encoded)
+}
+
+enum AssetSource {
+    // servers_list.js built from config
+    Generated(Vec<u8>),
+    Embedded(&'static [u8]),
+    Disk(File,Metadata),
+}
+
+enum AssetError {
+    BadRequest,
+    // outside of the assets root
+    Forbidden,
+    NotFound,
+    // directory requested without the trailing slash
+    Directory,
+}
+
+// decoded path segments, `..` is never resolved so a request can't climb above the root
+fn path_segments(path : &str) -> Result<Vec<String>,AssetError> {
+    let decoded = percent_decode(path).ok_or(AssetError::BadRequest)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| AssetError::BadRequest)?;
+    if decoded.contains('\0') {
+        return Err(AssetError::BadRequest)
+    }
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return Err(AssetError::Forbidden),
+            segment if segment.contains('\\') => return Err(AssetError::Forbidden),
+            segment => segments.push(segment.to_string())
+        }
+    }
+    Ok(segments)
+}
+
+// resolved asset with the file name it was found under (index.html for directories)
+fn find_asset(path : &str) -> Result<(AssetSource,String),AssetError> {
+    let config = SERVER_CONFIG.get().ok_or(AssetError::NotFound)?;
+    let segments = path_segments(path)?;
+    let is_dir_path = segments.is_empty() || path.ends_with('/');
+    if segments.len() == 1 && segments[0] == "servers_list.js" && (!config.servers.is_empty() || config.assets_path.is_empty()) {
+        return Ok((AssetSource::Generated(generate_server_endpoint()),"servers_list.js".to_string()))
+    }
+    if config.assets_path.is_empty() {
+        let mut file_name = segments.join("/");
+        if segments.is_empty() || DEF_ASSETS.get_dir(&file_name).is_some() {
+            if !is_dir_path {
+                return Err(AssetError::Directory)
+            }
+            file_name = if segments.is_empty() { "index.html".to_string() } else { format!("{}/index.html",file_name) };
+        }
+        let file = DEF_ASSETS.get_file(&file_name).ok_or(AssetError::NotFound)?;
+        Ok((AssetSource::Embedded(file.contents()),file_name))
+    } else {
+        let root = Path::new(&config.assets_path);
+        let mut file_path = resolve_in_root(root,&root.join(segments.join("/")))?;
+        if file_path.is_dir() {
+            if !is_dir_path {
+                return Err(AssetError::Directory)
+            }
+            file_path = resolve_in_root(root,&file_path.join("index.html"))?;
+        }
+        let file = File::open(&file_path).map_err(|_| AssetError::NotFound)?;
+        let metadata = file.metadata().map_err(|_| AssetError::NotFound)?;
+        if !metadata.is_file() {
+            return Err(AssetError::NotFound)
+        }
+        let file_name = file_path.to_string_lossy().to_string();
+        Ok((AssetSource::Disk(file,metadata),file_name))
+    }
+}
+
+// symlinks are followed by canonicalize, the target must still be inside the root
+fn resolve_in_root(root : &Path,path : &Path) -> Result<PathBuf,AssetError> {
+    let resolved = path.canonicalize().map_err(|_| AssetError::NotFound)?;
+    if resolved.starts_with(root) {
+        Ok(resolved)
+    } else {
+        Err(AssetError::Forbidden)
+    }
+}
+
+pub fn mime_type(extension : &str) -> &'static str {
+    match extension {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json; charset=utf-8",
+        "webmanifest" => "application/manifest+json; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "ico" => "image/vnd.microsoft.icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "eot" => "application/vnd.ms-fontobject",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream"
+    }
+}
+
+// documents are revalidated on every load, scripts & styles hourly, other static files daily
+fn cache_control(extension : &str) -> &'static str {
+    match extension {
+        "html" | "htm" | "json" | "webmanifest" => "no-cache",
+        "js" | "mjs" | "css" | "map" => "public, max-age=3600",
+        _ => "public, max-age=86400"
+    }
+}
+
+fn file_extension(file_name : &str) -> String {
+    let name = file_name.rsplit('/').next().unwrap_or("");
+    match name.rsplit_once('.') {
+        Some((_,extension)) => extension.to_ascii_lowercase(),
+        None => "".to_string()
+    }
+}
+
+fn embedded_etag(content : &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    format!("\"{:x}\"",hasher.finish())
+}
+
+// each encoded representation has its own validator
+fn encoded_etag(etag : &str,encoding : Encoding) -> String {
+    format!("{}-{}\"",etag.trim_end_matches('"'),encoding.as_str())
+}
+
+fn is_etag_matched(if_none_match : &str,etag : &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',').map(|tag| tag.trim()).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// If-None-Match takes precedence over If-Modified-Since
+fn is_not_modified(request : &Request,etag : &str,modified_secs : Option<i64>) -> bool {
+    if let Some(if_none_match) = request.headers.get("If-None-Match") {
+        return is_etag_matched(if_none_match,etag)
+    }
+    match (request.headers.get("If-Modified-Since").and_then(|since| parse_http_date(since)),modified_secs) {
+        (Some(since),Some(modified)) => modified <= since,
+        _ => false
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    Full,
+    Partial(u64,u64),
+    Unsatisfiable
+}
+
+// single `bytes=` range, multiple or invalid ranges are answered with the full content
+fn parse_range(range : &str,len : u64) -> RangeRequest {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full
+    }
+    let Some((start,end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full
+    };
+    let (start,end) = (start.trim(),end.trim());
+    let range = if start.is_empty() {
+        // suffix range : last n bytes
+        match end.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix),len.saturating_sub(1)),
+            Err(_) => return RangeRequest::Full
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Full
+        };
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            match end.parse::<u64>() {
+                // a last position before the first one is not a range
+                Ok(end) if end < start => return RangeRequest::Full,
+                Ok(end) => end.min(len.saturating_sub(1)),
+                Err(_) => return RangeRequest::Full
+            }
+        };
+        (start,end)
+    };
+    if len == 0 || range.0 >= len {
+        return RangeRequest::Unsatisfiable
+    }
+    RangeRequest::Partial(range.0,range.1)
+}
+
+fn not_modified(builder : ResponseBuilder) -> Response {
+    let mut response = builder.empty();
+    response.status = StatusCode::NOT_MODIFIED;
+    response.remove_header("Content-Type");
+    response
+}
+
+pub fn serve_asset(request : &Request) -> Response {
+    let (source,file_name) = match find_asset(request.path.trim()) {
+        Ok(asset) => asset,
+        Err(AssetError::BadRequest) => return Response::res_400(),
+        Err(AssetError::Forbidden) => return Response::res_403(),
+        Err(AssetError::NotFound) => return Response::res_404(),
+        Err(AssetError::Directory) => return Response::res_301(&format!("{}/",request.path.trim()))
+    };
+    let extension = file_extension(&file_name);
+    let content_type = mime_type(&extension);
+    let builder = Response::builder(StatusCode::OK)
+        .header("Content-Type",content_type);
+    match source {
+        AssetSource::Generated(content) => {
+            builder.header("Cache-Control","no-cache").bytes(content)
+        }
+        AssetSource::Embedded(content) => {
+            let mut etag = embedded_etag(content);
+            let mut body = content;
+            let mut builder = builder.header("Cache-Control",cache_control(&extension));
+            if is_compressible(content_type) {
+                builder = builder.header("Vary","Accept-Encoding");
+                if let Some(encoding) = negotiate(request.headers.get("Accept-Encoding")) {
+                    if let Some(encoded) = precompressed_asset(&file_name,encoding) {
+                        etag = encoded_etag(&etag,encoding);
+                        body = encoded;
+                        builder = builder.header("Content-Encoding",encoding.as_str());
+                    }
+                }
+            }
+            let builder = builder.header("ETag",&etag);
+            if is_not_modified(request,&etag,None) {
+                return not_modified(builder)
+            }
+            builder.bytes(body.to_vec())
+        }
+        AssetSource::Disk(mut file,metadata) => {
+            let len = metadata.len();
+            let modified_secs = metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs() as i64);
+            let mut etag = format!("\"{:x}-{:x}\"",len,modified_secs.unwrap_or(0));
+            let mut builder = builder
+                .header("Cache-Control",cache_control(&extension))
+                .header("Accept-Ranges","bytes");
+            if let Ok(modified) = metadata.modified() {
+                builder = builder.header("Last-Modified",&format_http_date(modified));
+            }
+            // text files are encoded on the fly, ranges are only served from the identity content
+            let mut encoding = None;
+            if is_compressible(content_type) {
+                builder = builder.header("Vary","Accept-Encoding");
+                if len <= MAX_ENCODED_FILE_SIZE && request.headers.get("Range").is_none() {
+                    encoding = negotiate(request.headers.get("Accept-Encoding"));
+                }
+            }
+            let identity_etag = etag.clone();
+            if let Some(encoding) = encoding {
+                etag = encoded_etag(&etag,encoding);
+            }
+            let builder = builder.header("ETag",&etag);
+            if is_not_modified(request,&etag,modified_secs) {
+                return not_modified(builder)
+            }
+            if let Some(encoding) = encoding {
+                return match encoded_file(&file_name,encoding,&identity_etag,&mut file) {
+                    Some(encoded) => builder.header("Content-Encoding",encoding.as_str()).bytes(encoded),
+                    None => Response::res_500()
+                }
+            }
+            // If-Range with another validator asks for the full content
+            let range = request.headers.get("Range")
+                .filter(|_| request.headers.get("If-Range").is_none_or(|if_range| is_etag_matched(if_range,&etag)));
+            match range.map(|range| parse_range(range,len)).unwrap_or(RangeRequest::Full) {
+                RangeRequest::Full => {
+                    builder.body(Body::Stream(Box::new(tokio::fs::File::from_std(file)),len))
+                }
+                RangeRequest::Partial(start,end) => {
+                    if file.seek(SeekFrom::Start(start)).is_err() {
+                        return Response::res_500()
+                    }
+                    let part_len = end - start + 1;
+                    let mut response = builder
+                        .header("Content-Range",&format!("bytes {}-{}/{}",start,end,len))
+                        .body(Body::Stream(Box::new(tokio::fs::File::from_std(file).take(part_len)),part_len));
+                    response.status = StatusCode::PARTIAL_CONTENT;
+                    response
+                }
+                RangeRequest::Unsatisfiable => {
+                    Response::builder(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header("Content-Range",&format!("bytes */{}",len))
+                        .empty()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_forbidden(path : &str) -> bool {
+        matches!(path_segments(path),Err(AssetError::Forbidden))
+    }
+
+    #[test]
+    fn encoded_dot_segments_are_rejected() {
+        assert!(is_forbidden("/%2e%2e/etc/passwd"));
+        assert!(is_forbidden("/%2E%2e/etc/passwd"));
+        assert!(is_forbidden("/css/%2e%2E/%2e%2e/etc/passwd"));
+        assert!(is_forbidden("/..%2fetc/passwd"));
+        assert!(is_forbidden("/..%2F..%2Fetc%2Fpasswd"));
+        assert!(is_forbidden("/%5c../etc/passwd"));
+        assert!(is_forbidden("/..%5c..%5cwindows"));
+    }
+
+    #[test]
+    fn double_encoded_dot_segments_stay_literal() {
+        let segments = path_segments("/%252e%252e/etc/passwd").ok().unwrap();
+        assert_eq!(segments,vec!["%2e%2e","etc","passwd"]);
+    }
+
+    #[test]
+    fn malformed_paths_are_bad_requests() {
+        assert!(matches!(path_segments("/%zz"),Err(AssetError::BadRequest)));
+        assert!(matches!(path_segments("/a%00.html"),Err(AssetError::BadRequest)));
+        assert!(matches!(path_segments("/%ff%fe"),Err(AssetError::BadRequest)));
+    }
+
+    #[test]
+    fn plain_paths_are_split() {
+        let segments = path_segments("/./css//main%20file.css").ok().unwrap();
+        assert_eq!(segments,vec!["css","main file.css"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_outside_the_root_are_forbidden() {
+        let base = std::env::temp_dir().join(format!("librespeed-assets-{}",std::process::id()));
+        let root = base.join("root");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret.txt"),b"secret").unwrap();
+        std::fs::write(root.join("index.html"),b"index").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"),root.join("escape.txt")).unwrap();
+        std::os::unix::fs::symlink(&outside,root.join("escape_dir")).unwrap();
+        std::os::unix::fs::symlink(root.join("index.html"),root.join("inside.html")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        assert!(matches!(resolve_in_root(&root,&root.join("escape.txt")),Err(AssetError::Forbidden)));
+        assert!(matches!(resolve_in_root(&root,&root.join("escape_dir/secret.txt")),Err(AssetError::Forbidden)));
+        assert!(resolve_in_root(&root,&root.join("inside.html")).is_ok_and(|path| path.starts_with(&root)));
+        assert!(matches!(resolve_in_root(&root,&root.join("missing.html")),Err(AssetError::NotFound)));
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn encoded_files_are_compressed_once_per_version() {
+        let path = std::env::temp_dir().join(format!("librespeed-encoded-{}.js",std::process::id()));
+        let path_name = path.to_string_lossy().to_string();
+        let open = |content : &[u8]| {
+            std::fs::write(&path,content).unwrap();
+            File::open(&path).unwrap()
+        };
+        let first = encoded_file(&path_name,Encoding::Gzip,"\"1\"",&mut open(&[b'a';4096])).unwrap();
+        // same version, served from the cache without reading the file
+        let cached = encoded_file(&path_name,Encoding::Gzip,"\"1\"",&mut open(b"changed")).unwrap();
+        assert_eq!(first,cached);
+        let changed = encoded_file(&path_name,Encoding::Gzip,"\"2\"",&mut open(b"changed")).unwrap();
+        assert_ne!(first,changed);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn encoded_files_cache_is_bounded() {
+        let mut cache = EncodedFiles::default();
+        let content = vec![0;MAX_ENCODED_CACHE_SIZE / 2];
+        cache.insert("a",Encoding::Gzip,"1",&content);
+        cache.insert("a",Encoding::Gzip,"2",&content);
+        assert_eq!(cache.size,content.len());
+        cache.insert("b",Encoding::Gzip,"1",&content);
+        cache.insert("c",Encoding::Brotli,"1",&content);
+        assert!(cache.size <= MAX_ENCODED_CACHE_SIZE);
+        assert!(cache.get("c",Encoding::Brotli,"1").is_some());
+        assert!(cache.get("c",Encoding::Gzip,"1").is_none());
+    }
+
+    #[test]
+    fn invalid_ranges_are_ignored() {
+        assert_eq!(parse_range("bytes=500-100",1000),RangeRequest::Full);
+        assert_eq!(parse_range("bytes=a-100",1000),RangeRequest::Full);
+        assert_eq!(parse_range("bytes=-a",1000),RangeRequest::Full);
+        assert_eq!(parse_range("items=0-10",1000),RangeRequest::Full);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_kept() {
+        assert_eq!(parse_range("bytes=1000-1100",1000),RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-",1000),RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0",1000),RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=100-200",1000),RangeRequest::Partial(100,200));
+        assert_eq!(parse_range("bytes=900-2000",1000),RangeRequest::Partial(900,999));
+        assert_eq!(parse_range("bytes=-100",1000),RangeRequest::Partial(900,999));
+    }
+}
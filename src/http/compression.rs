@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::OnceLock;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use include_dir::Dir;
+use log::{info, trace};
+use crate::config::{DEF_ASSETS, SERVER_CONFIG};
+use crate::http::assets::mime_type;
+use crate::http::response::{Body, Response};
+
+/*Content-Encoding negotiation, speedtest payloads (garbage & uploads) are never compressed*/
+
+// smaller bodies don't gain enough to pay for the encoding headers
+const MIN_COMPRESS_SIZE : usize = 128;
+const BROTLI_QUALITY : u32 = 9;
+const BROTLI_WINDOW : u32 = 22;
+const GZIP_LEVEL : u32 = 6;
+const ZSTD_LEVEL : i32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+// server preference when the client gives equal weights
+const ENCODINGS : [Encoding;3] = [Encoding::Zstd,Encoding::Brotli,Encoding::Gzip];
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Encoding::Zstd => 0,
+            Encoding::Brotli => 1,
+            Encoding::Gzip => 2,
+        }
+    }
+}
+
+// embedded asset path -> encoded variants, indexed by Encoding::index
+static PRECOMPRESSED_ASSETS : OnceLock<HashMap<String,[Option<Vec<u8>>;3]>> = OnceLock::new();
+
+pub fn init_precompressed_assets() {
+    if SERVER_CONFIG.get().is_some_and(|config| !config.assets_path.is_empty()) {
+        return
+    }
+    PRECOMPRESSED_ASSETS.get_or_init(|| {
+        let mut assets = HashMap::new();
+        collect_precompressed(&DEF_ASSETS,&mut assets);
+        info!("Precompressed {} embedded assets.",assets.len());
+        assets
+    });
+}
+
+fn collect_precompressed(dir : &Dir,assets : &mut HashMap<String,[Option<Vec<u8>>;3]>) {
+    for file in dir.files() {
+        let content = file.contents();
+        let path = file.path().to_string_lossy().replace('\\',"/");
+        let extension = path.rsplit_once('.').map(|(_,extension)| extension.to_ascii_lowercase()).unwrap_or_default();
+        if !is_compressible(mime_type(&extension)) || content.len() < MIN_COMPRESS_SIZE {
+            continue
+        }
+        let variants = ENCODINGS.map(|encoding| compress(encoding,content).filter(|encoded| encoded.len() < content.len()));
+        assets.insert(path,variants);
+    }
+    for dir in dir.dirs() {
+        collect_precompressed(dir,assets);
+    }
+}
+
+pub fn precompressed_asset(path : &str,encoding : Encoding) -> Option<&'static [u8]> {
+    PRECOMPRESSED_ASSETS.get()?.get(path)?[encoding.index()].as_deref()
+}
+
+pub fn is_compressible(content_type : &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime.starts_with("text/")
+        || matches!(mime,"application/json" | "application/manifest+json" | "application/xml" | "image/svg+xml" | "application/wasm" | "image/vnd.microsoft.icon")
+}
+
+// highest q-value wins, `*` covers codings that are not listed, q=0 refuses
+pub fn negotiate(accept_encoding : Option<&String>) -> Option<Encoding> {
+    let accept_encoding = accept_encoding?;
+    let mut weights : [Option<f32>;3] = [None;3];
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .next()
+            .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        match coding.as_str() {
+            "zstd" => weights[Encoding::Zstd.index()] = Some(quality),
+            "br" => weights[Encoding::Brotli.index()] = Some(quality),
+            "gzip" | "x-gzip" => weights[Encoding::Gzip.index()] = Some(quality),
+            "*" => wildcard = Some(quality),
+            _ => {}
+        }
+    }
+    let mut selected : Option<(Encoding,f32)> = None;
+    for encoding in ENCODINGS {
+        let quality = weights[encoding.index()].or(wildcard).unwrap_or(0.0);
+        if quality > 0.0 && selected.is_none_or(|(_,best)| quality > best) {
+            selected = Some((encoding,quality));
+        }
+    }
+    selected.map(|(encoding,_)| encoding)
+}
+
+pub fn compress(encoding : Encoding,content : &[u8]) -> Option<Vec<u8>> {
+    let encoded = match encoding {
+        Encoding::Zstd => zstd::encode_all(content,ZSTD_LEVEL),
+        Encoding::Brotli => {
+            let mut encoded = Vec::new();
+            // the stream is finished when the writer is dropped
+            let written = {
+                let mut writer = brotli::CompressorWriter::new(&mut encoded,4096,BROTLI_QUALITY,BROTLI_WINDOW);
+                writer.write_all(content).and_then(|_| writer.flush())
+            };
+            written.map(|_| encoded)
+        }
+        Encoding::Gzip => {
+            let mut writer = GzEncoder::new(Vec::new(),Compression::new(GZIP_LEVEL));
+            writer.write_all(content).and_then(|_| writer.finish())
+        }
+    };
+    match encoded {
+        Ok(encoded) => Some(encoded),
+        Err(e) => {
+            trace!("Error compress {} : {e}",encoding.as_str());
+            None
+        }
+    }
+}
+
+/*in memory responses (json, html, generated scripts), streamed & garbage bodies are sent as is*/
+pub fn compress_response(response : &mut Response,accept_encoding : Option<&String>) {
+    if response.header("Content-Encoding").is_some() || !response.status.is_success() {
+        return
+    }
+    if !response.header("Content-Type").is_some_and(is_compressible) {
+        return
+    }
+    let Body::Bytes(content) = &response.body else {
+        return
+    };
+    if content.len() < MIN_COMPRESS_SIZE {
+        return
+    }
+    let encoded = negotiate(accept_encoding)
+        .and_then(|encoding| compress(encoding,content).map(|encoded| (encoding,encoded)))
+        .filter(|(_,encoded)| encoded.len() < content.len());
+    // assets already vary on it
+    let varies = response.headers.iter()
+        .filter(|(name,_)| name.eq_ignore_ascii_case("Vary"))
+        .any(|(_,value)| value.split(',').any(|field| field.trim().eq_ignore_ascii_case("Accept-Encoding")));
+    if !varies {
+        response.append_header("Vary","Accept-Encoding");
+    }
+    if let Some((encoding,encoded)) = encoded {
+        response.set_header("Content-Encoding",encoding.as_str());
+        response.body = Body::Bytes(encoded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use super::*;
+
+    fn vary_count(response : &Response) -> usize {
+        response.headers.iter().filter(|(name,_)| name.eq_ignore_ascii_case("Vary")).count()
+    }
+
+    fn json_response() -> Response {
+        Response::builder(StatusCode::OK)
+            .header("Content-Type","application/json")
+            .bytes(vec![b'a';MIN_COMPRESS_SIZE * 4])
+    }
+
+    #[test]
+    fn vary_is_added_once() {
+        let mut response = json_response();
+        compress_response(&mut response,Some(&"gzip".to_string()));
+        assert_eq!(response.header("Content-Encoding"),Some("gzip"));
+        assert_eq!(vary_count(&response),1);
+    }
+
+    #[test]
+    fn existing_vary_is_kept() {
+        for vary in ["Accept-Encoding","origin, accept-encoding"] {
+            let mut response = json_response();
+            response.append_header("Vary",vary);
+            compress_response(&mut response,Some(&"br".to_string()));
+            assert_eq!(vary_count(&response),1,"{vary}");
+            assert_eq!(response.header("Vary"),Some(vary));
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::config::{CorsConfig, SERVER_CONFIG};
+use crate::http::response::Response;
+
+/*CORS policy from `[cors]` config, applied to every response*/
+
+fn is_origin_allowed(cors : &CorsConfig,origin : &str) -> bool {
+    cors.allowed_origins.iter().any(|allowed| allowed == "*" || allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+pub fn cors_headers(origin : Option<&String>) -> Vec<(&'static str,String)> {
+    let cors = &SERVER_CONFIG.get().unwrap().cors;
+    let wildcard = cors.allowed_origins.iter().any(|allowed| allowed == "*");
+    let mut headers = Vec::new();
+    // wildcard does not depend on the request origin, credentials are refused with it at startup
+    if wildcard {
+        headers.push(("Access-Control-Allow-Origin","*".to_string()));
+        return headers
+    }
+    if let Some(origin) = origin.filter(|origin| is_origin_allowed(cors,origin)) {
+        headers.push(("Access-Control-Allow-Origin",origin.to_string()));
+        if cors.allow_credentials {
+            headers.push(("Access-Control-Allow-Credentials","true".to_string()));
+        }
+    }
+    headers.push(("Vary","Origin".to_string()));
+    headers
+}
+
+pub fn apply_cors(response : &mut Response,origin : Option<&String>) {
+    for (name,value) in cors_headers(origin) {
+        if name == "Vary" {
+            response.append_header(name,&value);
+        } else {
+            response.set_header(name,&value);
+        }
+    }
+}
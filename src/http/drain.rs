@@ -0,0 +1,40 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use crate::http::limiter;
+
+/*Graceful shutdown : connections finish their current exchange then close, the server waits for them*/
+
+const DRAIN_POLL_INTERVAL : Duration = Duration::from_millis(100);
+
+static DRAINING: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn draining_sender() -> &'static watch::Sender<bool> {
+    DRAINING.get_or_init(|| watch::channel(false).0)
+}
+
+pub fn start_drain() {
+    draining_sender().send_replace(true);
+}
+
+pub fn is_draining() -> bool {
+    *draining_sender().borrow()
+}
+
+// resolves once the drain started, right away when it already has
+pub async fn drain_started() {
+    let mut receiver = draining_sender().subscribe();
+    let _ = receiver.wait_for(|draining| *draining).await;
+}
+
+/*open connections when the grace period ended, 0 once all of them closed*/
+pub async fn wait_connections(grace_period : Duration) -> usize {
+    let deadline = Instant::now() + grace_period;
+    loop {
+        let open_connections = limiter::open_connections();
+        if open_connections == 0 || Instant::now() >= deadline {
+            return open_connections
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
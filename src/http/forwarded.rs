@@ -0,0 +1,215 @@
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use case_insensitive_hashmap::CaseInsensitiveHashMap as CIHashMap;
+
+/*Client address behind reverse proxies, forwarding headers are only believed from trusted peers*/
+
+static TRUSTED_PROXIES: OnceLock<Vec<Cidr>> = OnceLock::new();
+static CLIENT_IP_HEADER: OnceLock<ClientIpHeader> = OnceLock::new();
+
+/*the one forwarding header believed from trusted proxies, the others are ignored*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientIpHeader {
+    Forwarded,
+    XForwardedFor,
+    XRealIp
+}
+
+impl ClientIpHeader {
+    pub fn from_config(header : &str) -> Option<Self> {
+        match header.to_ascii_lowercase().as_str() {
+            "forwarded" => Some(ClientIpHeader::Forwarded),
+            "x-forwarded-for" => Some(ClientIpHeader::XForwardedFor),
+            "x-real-ip" => Some(ClientIpHeader::XRealIp),
+            _ => None
+        }
+    }
+
+    fn hops(&self,headers : &CIHashMap<String>) -> Vec<Option<IpAddr>> {
+        match self {
+            ClientIpHeader::Forwarded => headers.get("Forwarded").map(|forwarded| forwarded_hops(forwarded)),
+            ClientIpHeader::XForwardedFor => headers.get("X-Forwarded-For").map(|x_forwarded_for| x_forwarded_for_hops(x_forwarded_for)),
+            ClientIpHeader::XRealIp => headers.get("X-Real-IP").map(|x_real_ip| vec![parse_node(x_real_ip)])
+        }.unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network : IpAddr,
+    prefix : u8
+}
+
+impl Cidr {
+    // `10.0.0.0/8`, `fd00::/8` or a single address
+    pub fn parse(cidr : &str) -> Option<Self> {
+        let (address,prefix) = match cidr.trim().split_once('/') {
+            Some((address,prefix)) => (address,Some(prefix.parse::<u8>().ok()?)),
+            None => (cidr.trim(),None)
+        };
+        let network = address.parse::<IpAddr>().ok()?.to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return None
+        }
+        Some(Cidr { network, prefix })
+    }
+
+    pub fn contains(&self,ip : IpAddr) -> bool {
+        match (self.network,ip.to_canonical()) {
+            (IpAddr::V4(network),IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network),IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false
+        }
+    }
+}
+
+pub fn init_trusted_proxies(trusted_proxies : &[String],client_ip_header : &str) -> std::io::Result<()> {
+    let Some(client_ip_header) = ClientIpHeader::from_config(client_ip_header) else {
+        return Err(std::io::Error::other(format!("Invalid client_ip_header : {}, use forwarded, x-forwarded-for or x-real-ip",client_ip_header)))
+    };
+    let mut proxies = Vec::new();
+    for proxy in trusted_proxies {
+        match Cidr::parse(proxy) {
+            Some(cidr) => proxies.push(cidr),
+            None => return Err(std::io::Error::other(format!("Invalid trusted_proxies entry : {}",proxy)))
+        }
+    }
+    TRUSTED_PROXIES.get_or_init(|| proxies);
+    CLIENT_IP_HEADER.get_or_init(|| client_ip_header);
+    Ok(())
+}
+
+fn is_trusted(ip : IpAddr) -> bool {
+    TRUSTED_PROXIES.get().is_some_and(|proxies| proxies.iter().any(|proxy| proxy.contains(ip)))
+}
+
+pub fn is_trusted_proxy(remote_addr : &str) -> bool {
+    remote_addr.parse::<IpAddr>().is_ok_and(is_trusted)
+}
+
+// `192.0.2.1`, `192.0.2.1:8080`, `[2001:db8::1]:8080` or a bare ipv6
+fn parse_node(node : &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse::<IpAddr>().ok()
+    }
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip)
+    }
+    node.rsplit_once(':').and_then(|(ip,_)| ip.parse::<IpAddr>().ok())
+}
+
+// RFC 7239 `Forwarded: for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`, None for unknown & obfuscated nodes
+fn forwarded_hops(forwarded : &str) -> Vec<Option<IpAddr>> {
+    forwarded.split(',')
+        .filter_map(|element| {
+            element.split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name,_)| name.trim().eq_ignore_ascii_case("for"))
+                .map(|(_,node)| parse_node(node))
+        })
+        .collect()
+}
+
+fn x_forwarded_for_hops(x_forwarded_for : &str) -> Vec<Option<IpAddr>> {
+    x_forwarded_for.split(',').map(parse_node).collect()
+}
+
+pub fn client_addr(headers : &CIHashMap<String>,remote_addr : &str) -> String {
+    let Ok(peer) = remote_addr.parse::<IpAddr>() else {
+        return remote_addr.to_string()
+    };
+    if !is_trusted(peer) {
+        return remote_addr.to_string()
+    }
+    let client_ip_header = CLIENT_IP_HEADER.get().copied().unwrap_or(ClientIpHeader::XForwardedFor);
+    forwarded_client(headers,peer,client_ip_header).to_string()
+}
+
+// right-most hop of the configured header that is not a trusted proxy, walking back from the trusted peer
+fn forwarded_client(headers : &CIHashMap<String>,peer : IpAddr,client_ip_header : ClientIpHeader) -> IpAddr {
+    let hops = client_ip_header.hops(headers);
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        // a malformed hop can't be trusted to tell anything further, keep the last known one
+        let Some(hop) = hop else {
+            break
+        };
+        client = hop.to_canonical();
+        if !is_trusted(client) {
+            break
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY : &str = "127.0.0.1";
+
+    fn headers(pairs : &[(&str,&str)]) -> CIHashMap<String> {
+        init_trusted_proxies(&["127.0.0.0/8".to_string(),"10.0.0.0/8".to_string()],"x-forwarded-for").unwrap();
+        let mut headers = CIHashMap::new();
+        for (name,value) in pairs {
+            headers.insert(name.to_string(),value.to_string());
+        }
+        headers
+    }
+
+    fn client(headers : &CIHashMap<String>,client_ip_header : ClientIpHeader) -> String {
+        forwarded_client(headers,PROXY.parse().unwrap(),client_ip_header).to_string()
+    }
+
+    #[test]
+    fn x_real_ip_ignores_injected_headers() {
+        // nginx `proxy_set_header X-Real-IP $remote_addr` passing the client headers through
+        let headers = headers(&[
+            ("X-Real-IP","198.51.100.7"),
+            ("X-Forwarded-For","1.2.3.4"),
+            ("Forwarded","for=1.2.3.4")
+        ]);
+        assert_eq!(client(&headers,ClientIpHeader::XRealIp),"198.51.100.7");
+    }
+
+    #[test]
+    fn configured_header_only() {
+        let headers = headers(&[("X-Forwarded-For","1.2.3.4")]);
+        assert_eq!(client(&headers,ClientIpHeader::XRealIp),PROXY);
+        assert_eq!(client(&headers,ClientIpHeader::Forwarded),PROXY);
+        assert_eq!(client(&headers,ClientIpHeader::XForwardedFor),"1.2.3.4");
+    }
+
+    #[test]
+    fn right_most_untrusted_hop() {
+        // the client prepended a forged hop, the proxy appended the real one
+        let headers = headers(&[
+            ("X-Forwarded-For","1.2.3.4, 198.51.100.7, 10.0.0.2"),
+            ("Forwarded","for=1.2.3.4, for=\"[2001:db8::1]:4711\", for=10.0.0.2")
+        ]);
+        assert_eq!(client(&headers,ClientIpHeader::XForwardedFor),"198.51.100.7");
+        assert_eq!(client(&headers,ClientIpHeader::Forwarded),"2001:db8::1");
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let headers = headers(&[("X-Real-IP","1.2.3.4")]);
+        assert_eq!(client_addr(&headers,"203.0.113.9"),"203.0.113.9");
+    }
+
+    #[test]
+    fn header_names() {
+        assert_eq!(ClientIpHeader::from_config("X-Real-IP"),Some(ClientIpHeader::XRealIp));
+        assert_eq!(ClientIpHeader::from_config("forwarded"),Some(ClientIpHeader::Forwarded));
+        assert_eq!(ClientIpHeader::from_config("x-forwarded-for, x-real-ip"),None);
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use bytes::{Bytes, BytesMut};
+use crate::config::SERVER_CONFIG;
+
+/*Download payload, random modes keep compressing links & VPNs from shrinking the measured data*/
+
+// random pool size whatever the chunk size, a repeat is beyond deflate (32 KiB), brotli (16 MiB) & zstd windows
+const POOL_BYTES : usize = 32 * 1024 * 1024;
+
+static GARBAGE_POOL : OnceLock<Vec<Bytes>> = OnceLock::new();
+static SEED_COUNTER : AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GarbageMode {
+    // zero bytes, cheapest but compressible
+    Zeros,
+    // chunks from a random pool generated at startup
+    Random,
+    // pool chunks masked with a fresh random key for every chunk
+    Stream,
+}
+
+impl GarbageMode {
+    pub fn from_config(mode : &str) -> Option<Self> {
+        match mode {
+            "zeros" => Some(GarbageMode::Zeros),
+            "random" => Some(GarbageMode::Random),
+            "stream" => Some(GarbageMode::Stream),
+            _ => None
+        }
+    }
+}
+
+// bytes per chunk written to the connection
+fn chunk_size() -> usize {
+    SERVER_CONFIG.get().map(|config| config.garbage_chunk_size).unwrap_or(524288)
+}
+
+fn garbage_mode() -> GarbageMode {
+    SERVER_CONFIG.get()
+        .and_then(|config| GarbageMode::from_config(&config.garbage_mode))
+        .unwrap_or(GarbageMode::Random)
+}
+
+// splitmix64, fast and statistically good enough to be incompressible
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_nanos() as u64).unwrap_or(0);
+        let counter = SEED_COUNTER.fetch_add(1,Ordering::Relaxed);
+        Rng(time ^ counter.wrapping_mul(0xD1B5_4A32_D192_ED03))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn chunk(&mut self,size : usize) -> Bytes {
+        let mut chunk = vec![0;size];
+        for bytes in chunk.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            bytes.copy_from_slice(&random[..bytes.len()]);
+        }
+        Bytes::from(chunk)
+    }
+}
+
+pub fn init_garbage() {
+    GARBAGE_POOL.get_or_init(|| {
+        match garbage_mode() {
+            GarbageMode::Zeros => vec![Bytes::from(vec![0;chunk_size()])],
+            GarbageMode::Random | GarbageMode::Stream => random_pool(chunk_size())
+
+        }
+    });
+}
+
+fn random_pool(chunk_size : usize) -> Vec<Bytes> {
+    let mut rng = Rng::new();
+    let chunks = POOL_BYTES.div_ceil(chunk_size).max(2);
+    (0..chunks).map(|_| rng.chunk(chunk_size)).collect()
+}
+
+/*size of one download, ckSize volume or a duration bounded by max_download_bytes*/
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadLimit {
+    pub bytes : u64,
+    pub duration : Option<Duration>,
+}
+
+// source xor a repeated 64 bit key, one vectorized pass into a buffer reused once the previous chunk is sent
+fn mask_chunk(buffer : &mut BytesMut,source : &[u8],key : u64) -> Bytes {
+    buffer.reserve(source.len());
+    buffer.extend_from_slice(source);
+    let mut words = buffer.chunks_exact_mut(8);
+    for word in &mut words {
+        let masked = u64::from_ne_bytes(word.try_into().unwrap()) ^ key;
+        word.copy_from_slice(&masked.to_ne_bytes());
+    }
+    for (byte,key) in words.into_remainder().iter_mut().zip(key.to_ne_bytes()) {
+        *byte ^= key;
+    }
+    buffer.split().freeze()
+}
+
+/*chunks of one download response, None once the limit is reached*/
+pub struct GarbageSource {
+    pool : &'static [Bytes],
+    index : usize,
+    // stream mode keys & buffer
+    rng : Option<Rng>,
+    buffer : BytesMut,
+    remaining : u64,
+    deadline : Option<Instant>
+}
+
+impl GarbageSource {
+    pub fn new(limit : DownloadLimit) -> Self {
+        let pool = GARBAGE_POOL.get_or_init(|| vec![Bytes::from(vec![0;chunk_size()])]);
+        Self::from_pool(limit,pool,garbage_mode())
+    }
+
+    fn from_pool(limit : DownloadLimit,pool : &'static [Bytes],mode : GarbageMode) -> Self {
+        let mut rng = Rng::new();
+        GarbageSource {
+            pool,
+            // start at a different pool chunk for every download
+            index : rng.next_u64() as usize,
+            rng : if mode == GarbageMode::Stream { Some(rng) } else { None },
+            buffer : BytesMut::new(),
+            remaining : limit.bytes,
+            deadline : limit.duration.map(|duration| Instant::now() + duration),
+        }
+    }
+
+    pub fn next_chunk(&mut self) -> Option<Bytes> {
+        if self.remaining == 0 || self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return None
+        }
+        self.index = self.index.wrapping_add(1);
+        let source = &self.pool[self.index % self.pool.len()];
+        let chunk = match &mut self.rng {
+            Some(rng) => mask_chunk(&mut self.buffer,source,rng.next_u64()),
+            None => source.clone()
+        };
+        let chunk = if chunk.len() as u64 > self.remaining { chunk.slice(..self.remaining as usize) } else { chunk };
+        self.remaining -= chunk.len() as u64;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+    use crate::config::MIN_CHUNK_SIZE;
+    use super::*;
+
+    fn compression_ratio(data : &[u8]) -> f64 {
+        let mut encoder = DeflateEncoder::new(Vec::new(),Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap().len() as f64 / data.len() as f64
+    }
+
+    #[test]
+    fn random_chunks_do_not_compress() {
+        let mut rng = Rng::new();
+        let chunk = rng.chunk(chunk_size());
+        assert_eq!(chunk.len(),chunk_size());
+        let ratio = compression_ratio(&chunk);
+        assert!(ratio > 0.99,"random chunk compressed to {ratio}");
+    }
+
+    #[test]
+    fn zero_chunks_compress() {
+        assert!(compression_ratio(&vec![0;chunk_size()]) < 0.01);
+    }
+
+    #[test]
+    fn masked_chunks_of_one_pool_chunk_do_not_compress_together() {
+        let source = Rng::new().chunk(chunk_size());
+        let mut buffer = BytesMut::new();
+        let mut rng = Rng::new();
+        let masked = [mask_chunk(&mut buffer,&source,rng.next_u64()),mask_chunk(&mut buffer,&source,rng.next_u64())];
+        assert_eq!(masked[0].len(),source.len());
+        assert_ne!(masked[0],masked[1]);
+        // zstd's window spans both chunks, an unmasked repeat is found
+        let zstd_ratio = |data : &[u8]| zstd::encode_all(data,3).unwrap().len() as f64 / data.len() as f64;
+        assert!(zstd_ratio(&[source.as_ref(),source.as_ref()].concat()) < 0.6);
+        let ratio = zstd_ratio(&[masked[0].as_ref(),masked[1].as_ref()].concat());
+        assert!(ratio > 0.99,"masked chunks compressed to {ratio}");
+    }
+
+    // many consecutive chunks at the smallest chunk size, long distance matching with a window over the sample
+    fn consecutive_chunks_ratio(mode : GarbageMode) -> f64 {
+        let pool : &'static [Bytes] = Box::leak(random_pool(MIN_CHUNK_SIZE).into_boxed_slice());
+        assert!(pool.len() * MIN_CHUNK_SIZE >= POOL_BYTES);
+        let sample_bytes = 16 * 1024 * 1024;
+        let mut garbage = GarbageSource::from_pool(DownloadLimit { bytes: sample_bytes as u64, duration: None },pool,mode);
+        let mut encoder = zstd::stream::Encoder::new(Vec::new(),3).unwrap();
+        encoder.long_distance_matching(true).unwrap();
+        encoder.window_log(25).unwrap();
+        while let Some(chunk) = garbage.next_chunk() {
+            assert_eq!(chunk.len(),MIN_CHUNK_SIZE);
+            encoder.write_all(&chunk).unwrap();
+        }
+        encoder.finish().unwrap().len() as f64 / sample_bytes as f64
+    }
+
+    #[test]
+    fn consecutive_random_chunks_do_not_compress() {
+        let ratio = consecutive_chunks_ratio(GarbageMode::Random);
+        assert!(ratio > 0.99,"random download compressed to {ratio}");
+    }
+
+    #[test]
+    fn consecutive_stream_chunks_do_not_compress() {
+        let ratio = consecutive_chunks_ratio(GarbageMode::Stream);
+        assert!(ratio > 0.99,"stream download compressed to {ratio}");
+    }
+
+    #[test]
+    fn masking_is_reversible_for_odd_sizes() {
+        let source = (0..4099).map(|index| index as u8).collect::<Vec<u8>>();
+        let mut buffer = BytesMut::new();
+        let key = 0x0123_4567_89AB_CDEF;
+        let masked = mask_chunk(&mut buffer,&source,key);
+        assert_ne!(masked.as_ref(),source.as_slice());
+        assert_eq!(mask_chunk(&mut buffer,&masked,key).as_ref(),source.as_slice());
+    }
+
+    #[test]
+    fn downloads_stop_at_their_byte_limit() {
+        let mut garbage = GarbageSource::new(DownloadLimit { bytes: chunk_size() as u64 * 2 + 10, duration: None });
+        let sizes = std::iter::from_fn(|| garbage.next_chunk().map(|chunk| chunk.len())).collect::<Vec<_>>();
+        assert_eq!(sizes,vec![chunk_size(),chunk_size(),10]);
+    }
+}
@@ -0,0 +1,254 @@
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use bytes::Bytes;
+use case_insensitive_hashmap::CaseInsensitiveHashMap as CIHashMap;
+use h2::{Reason, RecvStream, SendStream};
+use h2::server::SendResponse;
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::select;
+use crate::http::garbage::GarbageSource;
+use crate::http::{drain, forwarded, MethodStr};
+use crate::http::request::{is_form_body, parse_form_body, parse_raw_path, request_limits, with_timeout, BodyStats, Request};
+use crate::http::response::{Body, Response};
+use crate::results::{measure, metrics};
+
+/*HTTP/2 (negotiated with ALPN over TLS), requests are served by the same route handler as HTTP/1.1*/
+
+const STREAM_WINDOW_SIZE : u32 = 1024 * 1024;
+const CONNECTION_WINDOW_SIZE : u32 = 8 * 1024 * 1024;
+const MAX_CONCURRENT_STREAMS : u32 = 128;
+const STREAM_BUFFER_SIZE : usize = 64 * 1024;
+
+// headers that are connection specific and forbidden in HTTP/2
+const HOP_BY_HOP_HEADERS : [&str;5] = ["connection","keep-alive","proxy-connection","transfer-encoding","upgrade"];
+
+pub async fn serve<S,F>(remote_addr : &str,client_subject : Option<&str>,stream : S,handler : F)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Send + Sync + 'static + Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>>
+{
+    let limits = request_limits();
+    let handshake = h2::server::Builder::new()
+        .initial_window_size(STREAM_WINDOW_SIZE)
+        .initial_connection_window_size(CONNECTION_WINDOW_SIZE)
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .max_header_list_size(limits.max_header_size as u32)
+        .handshake::<_,Bytes>(stream);
+    let mut connection = match with_timeout(limits.header_timeout,handshake).await {
+        Some(Ok(connection)) => connection,
+        Some(Err(e)) => {
+            trace!("Error h2 handshake : {e}");
+            return;
+        }
+        None => {
+            trace!("Error h2 handshake : timeout");
+            return;
+        }
+    };
+    let handler = Arc::new(handler);
+    // streams in progress, the connection only idles out without any
+    let active_streams = Arc::new(AtomicUsize::new(0));
+    let mut going_away = false;
+    loop {
+        // GOAWAY when the server drains, open streams complete before accept returns None
+        if !going_away && drain::is_draining() {
+            connection.graceful_shutdown();
+            going_away = true;
+        }
+        // accept is cancel safe, the connection keeps driving open streams when polled again
+        let accept = async {
+            if going_away {
+                return with_timeout(limits.keep_alive_timeout,connection.accept()).await
+            }
+            select! {
+                accepted = with_timeout(limits.keep_alive_timeout,connection.accept()) => accepted,
+                _ = drain::drain_started() => Some(None)
+            }
+        };
+        let accepted = match accept.await {
+            Some(None) if !going_away && drain::is_draining() => continue,
+            Some(Some(accepted)) => accepted,
+            Some(None) => break,
+            None if active_streams.load(Ordering::Relaxed) > 0 => continue,
+            None => {
+                trace!("h2 connection idle timeout");
+                break;
+            }
+        };
+        match accepted {
+            Ok((request,respond)) => {
+                let handler = handler.clone();
+                let remote_addr = remote_addr.to_string();
+                let client_subject = client_subject.map(str::to_string);
+                let active_streams = active_streams.clone();
+                active_streams.fetch_add(1,Ordering::Relaxed);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_stream(&remote_addr,client_subject,request,respond,handler).await {
+                        trace!("Error h2 stream : {e}")
+                    }
+                    active_streams.fetch_sub(1,Ordering::Relaxed);
+                });
+            }
+            Err(e) => {
+                trace!("Error h2 connection : {e}");
+                break;
+            }
+        }
+    }
+}
+
+async fn handle_stream<F>(remote_addr : &str,client_subject : Option<String>,request : http::Request<RecvStream>,mut respond : SendResponse<Bytes>,handler : Arc<F>) -> Result<(),h2::Error>
+where
+    F: Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>>
+{
+    let (parts,mut body) = request.into_parts();
+    //headers
+    let mut headers = CIHashMap::new();
+    for (name,value) in parts.headers.iter() {
+        if let Ok(value) = value.to_str() {
+            headers.insert(name.as_str().to_string(),value.to_string());
+        }
+    }
+    if let Some(authority) = parts.uri.authority() {
+        if headers.get("Host").is_none() {
+            headers.insert("Host".to_string(),authority.to_string());
+        }
+    }
+    let raw_path = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let (path,query_params) = parse_raw_path(raw_path);
+    //body, form bodies are kept for parsing, others are discarded and counted
+    let keep_body = is_form_body(&headers);
+    let mut body_stats = BodyStats::default();
+    let mut form_body = Vec::new();
+    let mut too_large = false;
+    let limits = request_limits();
+    loop {
+        let data = match with_timeout(limits.body_timeout,body.data()).await {
+            Some(Some(data)) => data,
+            Some(None) => break,
+            None => {
+                trace!("Error h2 read body : timeout");
+                body_stats.aborted = true;
+                break;
+            }
+        };
+        match data {
+            Ok(data) => {
+                body_stats.bytes += data.len() as u64;
+                let _ = body.flow_control().release_capacity(data.len());
+                if keep_body {
+                    if form_body.len() as u64 + data.len() as u64 > limits.max_form_body_size {
+                        too_large = true;
+                        break;
+                    }
+                    form_body.extend_from_slice(&data);
+                }
+            }
+            Err(e) => {
+                trace!("Error h2 read body : {e}");
+                body_stats.aborted = true;
+                break;
+            }
+        }
+    }
+    body_stats.duration = body_stats.start.elapsed();
+    let form_data = if keep_body { parse_form_body(&headers,&form_body) } else { None };
+    let remote_addr = forwarded::client_addr(&headers,remote_addr);
+    let response = if too_large {
+        let response = Response::res_413();
+        metrics::record_request(metrics::route_name(path),response.status);
+        response
+    } else {
+        handler(Request {
+            path: path.to_string(),
+            method: parts.method.as_str().to_method(),
+            remote_addr: remote_addr.clone(),
+            client_subject,
+            query_params,
+            headers,
+            form_data: form_data.unwrap_or_default(),
+            body_stats,
+        }).await
+    };
+    if body_stats.aborted {
+        return Ok(())
+    }
+    //response
+    let end_of_stream = response.head_only || response.body.len() == Some(0);
+    let mut send = respond.send_response(h2_head(&response)?,end_of_stream)?;
+    if end_of_stream {
+        return Ok(())
+    }
+    match response.body {
+        Body::Empty => Ok(()),
+        Body::Bytes(bytes) => {
+            send_data(&mut send,Bytes::from(bytes),true).await
+        }
+        Body::Garbage(limit) => {
+            let mut garbage = GarbageSource::new(limit);
+            let download_start = Instant::now();
+            let mut download_bytes = 0;
+            while let Some(chunk) = garbage.next_chunk() {
+                let chunk_len = chunk.len() as u64;
+                let sent = send_data(&mut send,chunk,false).await;
+                if sent.is_err() {
+                    measure::record_download(&remote_addr,download_bytes,download_start,Instant::now());
+                    return sent
+                }
+                download_bytes += chunk_len;
+            }
+            // duration downloads don't know their last chunk in advance
+            send_data(&mut send,Bytes::new(),true).await?;
+            measure::record_download(&remote_addr,download_bytes,download_start,Instant::now());
+            Ok(())
+        }
+        Body::Stream(mut reader,len) => {
+            let mut remaining = len;
+            let mut buffer = vec![0;STREAM_BUFFER_SIZE];
+            while remaining > 0 {
+                let read = reader.read(&mut buffer).await.map_err(|_| h2::Error::from(Reason::INTERNAL_ERROR))?;
+                if read == 0 {
+                    return Err(h2::Error::from(Reason::INTERNAL_ERROR))
+                }
+                remaining = remaining.saturating_sub(read as u64);
+                send_data(&mut send,Bytes::copy_from_slice(&buffer[..read]),remaining == 0).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn h2_head(response : &Response) -> Result<http::Response<()>,h2::Error> {
+    let mut builder = http::Response::builder().status(response.status);
+    for (name,value) in response.all_headers() {
+        let name = name.to_lowercase();
+        if !HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            builder = builder.header(name,value);
+        }
+    }
+    builder.body(()).map_err(|_| h2::Error::from(Reason::INTERNAL_ERROR))
+}
+
+// send respecting the peer flow control window, so large downloads are not buffered in memory
+async fn send_data(send : &mut SendStream<Bytes>,mut data : Bytes,end_of_stream : bool) -> Result<(),h2::Error> {
+    if data.is_empty() {
+        return send.send_data(data,end_of_stream)
+    }
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            None => return Err(h2::Error::from(Reason::CANCEL))
+        };
+        if capacity == 0 {
+            continue;
+        }
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk,end_of_stream && data.is_empty())?;
+    }
+    Ok(())
+}
@@ -3,6 +3,7 @@ use std::io::{Error};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use serde_json::Value;
+use case_insensitive_hashmap::CaseInsensitiveHashMap as CIHashMap;
 use tokio::io::{split, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
 use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
@@ -11,12 +12,23 @@ use indicatif::{ProgressBar, ProgressStyle};
 use crate::http::request::header_parser;
 use crate::http::tls::setup_tls_connector;
 
+// largest response body read by send_request
+const MAX_RESPONSE_SIZE : u64 = 1024 * 1024;
+
 pub struct HttpClient {
     pub host : String,
     pub path : String,
     pub stream : ClientStream
 }
 
+/*response of send_request, read until its end*/
+#[derive(Debug)]
+pub struct ClientResponse {
+    pub status : u16,
+    pub headers : CIHashMap<String>,
+    pub body : Vec<u8>
+}
+
 #[derive(Debug)]
 pub enum ClientStream {
     Tcp(TcpStream),
@@ -56,21 +68,81 @@ impl AsyncWrite for ClientStream {
 impl HttpClient {
 
     pub async fn open(url : &str) -> std::io::Result<Self> {
+        Self::open_with_ca(url,None).await
+    }
+
+    // ca_file adds a trusted root, for private CAs & test servers
+    pub async fn open_with_ca(url : &str,ca_file : Option<&str>) -> std::io::Result<Self> {
         let pared_url = Self::parse_url(url)?;
         let tcp_stream = TcpStream::connect(format!("{}:{}",pared_url.1,pared_url.2)).await?;
-        let stream = if pared_url.2 == 443 {
-            let tls_stream = setup_tls_connector(pared_url.1.clone(),tcp_stream).await;
+        let stream = if pared_url.0 == "https" {
+            let tls_stream = setup_tls_connector(pared_url.1.clone(),tcp_stream,ca_file).await?;
             ClientStream::Tls(Box::from(tls_stream))
         } else {
             ClientStream::Tcp(tcp_stream)
         };
+        let default_port = if pared_url.0 == "https" { 443 } else { 80 };
         Ok(HttpClient {
-            host : pared_url.1,
+            host : if pared_url.2 == default_port { pared_url.1 } else { format!("{}:{}",pared_url.1,pared_url.2) },
             path : pared_url.3,
             stream
         })
     }
 
+    // one request on the connection, closed once the response is read
+    pub async fn send_request(&mut self,method : &str,headers : &[(&str,&str)],body : &[u8]) -> std::io::Result<ClientResponse> {
+        let mut request = format!("{} /{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",method,self.path,self.host,body.len());
+        for (name,value) in headers {
+            request.push_str(&format!("{}: {}\r\n",name,value));
+        }
+        request.push_str("\r\n");
+        self.stream.write_all(request.as_bytes()).await?;
+        self.stream.write_all(body).await?;
+        self.stream.flush().await?;
+        let mut buf_reader = BufReader::with_capacity(8 * 1024,&mut self.stream);
+        let status_line = (&mut buf_reader).lines().next_line().await?.unwrap_or_default();
+        let status = status_line.split(' ').nth(1).and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| Error::other(format!("Invalid response status line : {}",status_line)))?;
+        let headers = header_parser(&mut buf_reader).await;
+        let mut body = Vec::new();
+        if method != "HEAD" && status != 204 && status != 304 {
+            if headers.get("Transfer-Encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+                body = Self::read_chunked(&mut buf_reader).await?;
+            } else {
+                let length = headers.get("Content-Length").and_then(|length| length.parse::<u64>().ok()).unwrap_or(MAX_RESPONSE_SIZE);
+                if length > MAX_RESPONSE_SIZE {
+                    return Err(Error::other("Response body too large"))
+                }
+                (&mut buf_reader).take(length).read_to_end(&mut body).await?;
+            }
+        }
+        Ok(ClientResponse { status, headers, body })
+    }
+
+    async fn read_chunked<R>(buf_reader : &mut BufReader<R>) -> std::io::Result<Vec<u8>>
+    where
+        R : AsyncReadExt + Unpin
+    {
+        let mut body = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            buf_reader.read_line(&mut line).await?;
+            let chunk_size = u64::from_str_radix(line.split(';').next().unwrap_or("").trim(),16)
+                .map_err(|_| Error::other("Invalid response chunk size"))?;
+            if chunk_size == 0 {
+                break;
+            }
+            if body.len() as u64 + chunk_size > MAX_RESPONSE_SIZE {
+                return Err(Error::other("Response body too large"))
+            }
+            buf_reader.take(chunk_size).read_to_end(&mut body).await?;
+            line.clear();
+            buf_reader.read_line(&mut line).await?;
+        }
+        Ok(body)
+    }
+
     pub async fn send_request_json(&mut self,packet : &[u8]) -> std::io::Result<Option<Value>> {
         self.stream.write_all(packet).await?;
         let mut read_data = Vec::new();
@@ -150,6 +222,10 @@ impl HttpClient {
                 (rest,"")
             };
             let port = if scheme == "https" { 443 } else { 80 };
+            let (host, port) = match host.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse::<i32>().map_err(|_| Error::other("Error parsing input url port"))?),
+                None => (host, port)
+            };
             Ok((scheme.to_string(),host.to_string(),port,path.to_string()))
         } else {
             Err(Error::other("Error parsing input url"))
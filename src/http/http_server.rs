@@ -1,23 +1,56 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use http::StatusCode;
 use log::{info, trace};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, split};
+use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tokio_rustls::TlsAcceptor;
-use crate::config::{ROUTES, SERVER_CONFIG};
+use crate::config::{ListenerConfig, ROUTES, SERVER_CONFIG};
 use crate::database::Database;
-use crate::http::{find_remote_ip_addr, get_chunk_count, Method};
-use crate::http::request::handle_socket;
+use crate::http::{acme, drain, find_remote_ip_addr, generate_server_list_json, get_download_limit, http2, join_methods, limiter, Method};
+use crate::http::assets::serve_asset;
+use crate::http::compression::compress_response;
+use crate::http::cors::apply_cors;
+use crate::http::request::{handle_socket, request_limits, with_timeout, Request};
 use crate::http::response::Response;
 
 use crate::http::routes::*;
 use crate::http::tcp_socket::TcpSocket;
-use crate::http::tls::setup_tls_acceptor;
+use crate::http::tls::{client_subject, has_certificate_for, requires_client_cert, setup_tls_acceptor, spawn_certificate_reloader};
 use crate::ip::ip_info::IPInfo;
+use crate::results::metrics;
 use crate::results::stats::handle_stat_page;
 
+// rejected connections are not counted, the 429 gets this long to be written
+const REJECTED_WRITE_TIMEOUT : Duration = Duration::from_secs(1);
+
+/*accept options of a bound listener, same order as the TcpSocket listeners*/
+#[derive(Clone)]
+pub struct Listener {
+    pub tls_acceptor: Option<TlsAcceptor>,
+    pub proxy_protocol: bool,
+    // plain listener redirecting every request to this https port
+    pub https_redirect: Option<u16>
+}
+
+impl Listener {
+    fn from_config(listener : &ListenerConfig,https_port : u16) -> std::io::Result<Self> {
+        let mut tls_acceptor = None;
+        if listener.tls {
+            tls_acceptor = Some(setup_tls_acceptor(&listener.tls_cert_file,&listener.tls_key_file)?);
+        }
+        Ok(Listener {
+            tls_acceptor,
+            proxy_protocol: listener.proxy_protocol,
+            https_redirect: if listener.redirect_to_https { Some(https_port) } else { None },
+        })
+    }
+}
+
 pub struct HttpServer {
     pub tcp_socket: TcpSocket,
-    pub tls_acceptor: Option<TlsAcceptor>
+    pub listeners: Vec<Listener>
 }
 
 impl HttpServer {
@@ -27,46 +60,96 @@ impl HttpServer {
         let tcp_socket = TcpSocket::make_listener(config)?;
         info!("Server started on {}",tcp_socket);
         info!("Server base url : {}/",config.base_url);
-        let mut tls_acceptor = None;
-        if config.enable_tls {
-            tls_acceptor = Some(setup_tls_acceptor(&config.tls_cert_file,&config.tls_key_file)?);
+        let https_port = config.listeners.iter().find(|listener| listener.tls).map(|listener| listener.port).unwrap_or(443);
+        // listeners passed by systemd without a matching entry use the first one
+        let mut listeners = Vec::new();
+        for index in 0..tcp_socket.listener_count() {
+            let listener = config.listeners.get(index).unwrap_or(&config.listeners[0]);
+            listeners.push(Listener::from_config(listener,https_port)?);
         }
         Ok(HttpServer {
             tcp_socket,
-            tls_acceptor
+            listeners
         })
     }
 
     pub async fn listen (&mut self, database : &mut Arc<Mutex<dyn Database + Send>>) {
         self.tcp_socket.spawn_signal_handler();
+        spawn_certificate_reloader();
+        acme::spawn_acme_manager();
         let mut shutdown_rx = self.tcp_socket.shutdown_tx.subscribe();
         loop {
 
             let tcp_accept = self.tcp_socket.accept(&mut shutdown_rx).await;
             let mut database = database.clone();
-            let tls_acceptor = self.tls_acceptor.clone();
 
             match tcp_accept {
-                Ok(Some((mut socket,_))) => {
+                Ok(Some((mut socket,_,index))) => {
+
+                    let listener = self.listeners[index].clone();
 
                     tokio::spawn(async move {
 
-                        let remote_addr = find_remote_ip_addr(&mut socket).await;
+                        let remote_addr = match find_remote_ip_addr(&mut socket,listener.proxy_protocol).await {
+                            Ok(remote_addr) => remote_addr,
+                            Err(e) => {
+                                trace!("Error remote address : {e}");
+                                return;
+                            }
+                        };
+                        let _connection_guard = match limiter::acquire_connection(&remote_addr) {
+                            Ok(connection_guard) => connection_guard,
+                            Err(retry_after) => {
+                                trace!("Connection limit reached, rejected {remote_addr}");
+                                Self::reject_connection(socket,&listener,retry_after).await;
+                                return;
+                            }
+                        };
 
-                        if let Some(tls_acceptor) = tls_acceptor {
+                        if let Some(https_port) = listener.https_redirect {
 
-                            let stream = tls_acceptor.accept(socket).await;
+                            let (socket_r,socket_w) = socket.split();
+                            let mut buff_reader = BufReader::with_capacity(8 * 1024,socket_r);
+                            let mut buff_writer = BufWriter::with_capacity(8 * 1024,socket_w);
+                            handle_socket(&remote_addr,None,&mut buff_reader,&mut buff_writer,|request| {
+                                Box::pin(async move {
+                                    acme::challenge_response(&request).unwrap_or_else(|| Self::redirect_https(&request,https_port))
+                                })
+                            }).await;
+
+                        } else if let Some(tls_acceptor) = listener.tls_acceptor {
+
+                            let stream = match with_timeout(request_limits().header_timeout,tls_acceptor.accept(socket)).await {
+                                Some(stream) => stream,
+                                None => {
+                                    trace!("Error tls handshake : timeout");
+                                    metrics::record_tls_handshake_failure();
+                                    return;
+                                }
+                            };
+                            let client_subject = stream.as_ref().ok().and_then(|stream| client_subject(stream.get_ref().1));
                             match stream {
+                                Ok(stream) if stream.get_ref().1.alpn_protocol() == Some(b"h2") => {
+
+                                    http2::serve(&remote_addr,client_subject.as_deref(),stream,move |request| {
+                                        let mut database = database.clone();
+                                        Box::pin(async move {
+                                            Self::route_request(request,&mut database).await
+                                        })
+                                    }).await;
+
+                                }
                                 Ok(stream) => {
 
                                     let (socket_r, socket_w) = split(stream);
                                     let mut buff_reader = BufReader::with_capacity(8 * 1024, socket_r);
                                     let mut buff_writer = BufWriter::with_capacity(8 * 1024,socket_w);
-                                    Self::handle_connection(&remote_addr,&mut buff_reader,&mut buff_writer,&mut database).await;
+                                    Self::handle_connection(&remote_addr,client_subject.as_deref(),&mut buff_reader,&mut buff_writer,&mut database).await;
 
                                 }
                                 Err(e) => {
-                                    trace!("Error tcp connection : {e}")
+                                    trace!("Error tls handshake : {e}");
+                                    metrics::record_tls_handshake_failure();
                                 }
                             }
 
@@ -75,7 +158,7 @@ impl HttpServer {
                             let (socket_r,socket_w) = socket.split();
                             let mut buff_reader = BufReader::with_capacity(8 * 1024,socket_r);
                             let mut buff_writer = BufWriter::with_capacity(8 * 1024,socket_w);
-                            Self::handle_connection(&remote_addr,&mut buff_reader,&mut buff_writer,&mut database).await;
+                            Self::handle_connection(&remote_addr,None,&mut buff_reader,&mut buff_writer,&mut database).await;
 
                         }
 
@@ -83,7 +166,8 @@ impl HttpServer {
 
                 }
                 Ok(None) => {
-                    info!("Shutdown signal received, stopping service ...");
+                    self.tcp_socket.close_listeners();
+                    Self::drain_connections().await;
                     info!("Bye 👋");
                     break;
                 }
@@ -95,51 +179,138 @@ impl HttpServer {
         }
     }
 
-    pub async fn handle_connection<R,W>(remote_addr : &str,buf_reader: &mut BufReader<R>,buf_writer : &mut BufWriter<W>,database : &mut Arc<Mutex<dyn Database + Send>>)
+    /*in flight tests get up to shutdown_grace_period to complete, idle connections close right away*/
+    async fn drain_connections() {
+        let grace_period = SERVER_CONFIG.get().unwrap().shutdown_grace_period;
+        let draining = limiter::open_connections();
+        info!("Shutting down, draining {draining} connections (grace period {grace_period}s) ...");
+        let drain_start = Instant::now();
+        drain::start_drain();
+        let remaining = drain::wait_connections(Duration::from_secs(grace_period)).await;
+        if remaining == 0 {
+            info!("All connections drained in {:.1}s",drain_start.elapsed().as_secs_f64());
+        } else {
+            info!("Grace period elapsed, closing {remaining} of {draining} connections");
+        }
+    }
+
+    /*fixed 429 to a plain http client over a connection limit, nothing is parsed & tls connections are closed right away*/
+    async fn reject_connection(mut socket : TcpStream,listener : &Listener,retry_after : u64) {
+        if listener.tls_acceptor.is_some() && listener.https_redirect.is_none() {
+            return
+        }
+        metrics::record_request("unknown",StatusCode::TOO_MANY_REQUESTS);
+        let response = format!("HTTP/1.1 429 Too Many Requests\r\nRetry-After: {retry_after}\r\nConnection: close\r\nContent-Length: 21\r\n\r\n429 too many requests");
+        // what the client already sent, unread data would reset the connection before it reads the answer
+        let _ = socket.try_read(&mut [0;4096]);
+        let _ = tokio::time::timeout(REJECTED_WRITE_TIMEOUT,async {
+            socket.write_all(response.as_bytes()).await?;
+            socket.shutdown().await
+        }).await;
+    }
+
+    fn redirect_https(request : &Request,https_port : u16) -> Response {
+        let Some(host) = request.headers.get("Host") else {
+            return Response::res_400()
+        };
+        // drop the plain port, keeping ipv6 literals in brackets
+        let host = match host.rsplit_once(':') {
+            Some((name,port)) if !port.contains(']') => name,
+            _ => host.as_str()
+        };
+        // only to names the https listener has a certificate for, any other Host would be an open redirect
+        if !has_certificate_for(host) {
+            return Response::res_400()
+        }
+        let port = if https_port == 443 { String::new() } else { format!(":{}",https_port) };
+        let mut query = request.query_params.to_query_string();
+        if !query.is_empty() {
+            query.insert(0,'?');
+        }
+        Response::res_308(&format!("https://{}{}{}{}",host,port,request.path,query))
+    }
+
+    pub async fn handle_connection<R,W>(remote_addr : &str,client_subject : Option<&str>,buf_reader: &mut BufReader<R>,buf_writer : &mut BufWriter<W>,database : &mut Arc<Mutex<dyn Database + Send>>)
         where
             R: AsyncReadExt + Unpin,
             W: AsyncWriteExt + Unpin
     {
-        handle_socket(remote_addr, buf_reader, buf_writer, |request|{
-
+        handle_socket(remote_addr, client_subject, buf_reader, buf_writer, |request|{
             let mut database = database.clone();
-
             Box::pin(async move {
-                if let Some(route) = ROUTES.get().unwrap().get(request.path.trim()) {
-                    match *route {
-                        "empty" => {
-                            Response::res_200("")
-                        }
-                        "garbage" => {
-                            let chunks = get_chunk_count(&request.query_params);
-                            Response::res_200_garbage(chunks)
-                        }
-                        "getIP" => {
-                            let ip_info = IPInfo::fetch_information(
-                                &request.remote_addr,
-                                request.query_params.get("isp").unwrap_or(&"false".to_string()).parse::<bool>().unwrap_or(false)).await;
-                            Response::res_200_json(&ip_info)
-                        }
-                        "results" => {
-                            show_result_route(&mut database,&request.query_params).await
-                        }
-                        "results/telemetry" => {
-                            telemetry_record_route(&mut database, &request).await
-                        }
-                        "stats" => {
-                            handle_stat_page(&request,&mut database).await
-                        }
-                        _ => {
-                            Response::res_404()
-                        }
+                Self::route_request(request,&mut database).await
+            })
+        }).await;
+    }
+
+    pub async fn route_request(request : Request,database : &mut Arc<Mutex<dyn Database + Send>>) -> Response {
+        if let Some(response) = acme::challenge_response(&request) {
+            return response
+        }
+        let route = ROUTES.get().unwrap().get(request.path.trim()).copied();
+        let allowed = allowed_methods(route);
+        let mut response = if let Err(retry_after) = limiter::check_request(&request.remote_addr,route) {
+            Response::res_429(retry_after)
+        } else if requires_client_cert(route) && request.client_subject.is_none() {
+            Response::res_403()
+        } else if request.method == Method::Options {
+            Response::res_204_options(&join_methods(allowed))
+        } else if !allowed.contains(&request.method) {
+            Response::res_405(&join_methods(allowed))
+        } else if request.method == Method::Head {
+            Self::dispatch_route(route,&request,database).await.without_body()
+        } else {
+            Self::dispatch_route(route,&request,database).await
+        };
+        metrics::record_request(route.unwrap_or("assets"),response.status);
+        compress_response(&mut response,request.headers.get("Accept-Encoding"));
+        apply_cors(&mut response,request.headers.get("Origin"));
+        response
+    }
+
+    async fn dispatch_route(route : Option<&str>,request : &Request,database : &mut Arc<Mutex<dyn Database + Send>>) -> Response {
+        if let Some(route) = route {
+            match route {
+                "empty" => {
+                    empty_route(request)
+                }
+                "garbage" => {
+                    match get_download_limit(&request.query_params) {
+                        Some(limit) => Response::res_200_garbage(limit),
+                        None => Response::res_400()
                     }
-                } else if matches!(request.method,Method::Get) {
-                    Response::res_200_fs(request.path.trim())
-                } else {
+                }
+                "getIP" => {
+                    let ip_info = IPInfo::fetch_information(
+                        &request.remote_addr,
+                        request.query_params.get("isp").unwrap_or(&"false".to_string()).parse::<bool>().unwrap_or(false)).await;
+                    Response::res_200_json(&ip_info)
+                }
+                "results" => {
+                    show_result_route(database,&request.query_params).await
+                }
+                "results/telemetry" => {
+                    telemetry_record_route(database, request).await
+                }
+                "stats" => {
+                    handle_stat_page(request,database).await
+                }
+                "ws" => {
+                    Response::res_400()
+                }
+                "servers.json" => {
+                    Response::res_200_json(&generate_server_list_json(request.headers.get("Host").map(String::as_str)))
+                }
+                "metrics" => {
+                    metrics::metrics_response()
+                }
+                _ => {
                     Response::res_404()
                 }
-            })
-        }).await;
+            }
+        } else {
+            serve_asset(request)
+        }
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use crate::config::{LimitsConfig, SERVER_CONFIG};
+use crate::http::forwarded::is_trusted_proxy;
+
+/*Per client ip limits : request rate (token bucket), open connections and hourly traffic budget*/
+
+const BUDGET_WINDOW : Duration = Duration::from_secs(3600);
+// suggested to clients over a connection limit, open connections free up without notice
+const CONNECTION_RETRY_AFTER : u64 = 5;
+// idle clients are swept at most this often, when a new client ip shows up
+const SWEEP_INTERVAL : Duration = Duration::from_secs(60);
+
+static CLIENTS: OnceLock<Mutex<Clients>> = OnceLock::new();
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct Clients {
+    by_ip : HashMap<String,ClientLimits>,
+    last_sweep : Instant
+}
+
+#[derive(Debug)]
+struct ClientLimits {
+    tokens : f64,
+    last_refill : Instant,
+    connections : usize,
+    budget_start : Instant,
+    budget_bytes : u64,
+}
+
+impl ClientLimits {
+    fn new(limits : &LimitsConfig) -> Self {
+        let now = Instant::now();
+        ClientLimits {
+            tokens: limits.burst as f64,
+            last_refill: now,
+            connections: 0,
+            budget_start: now,
+            budget_bytes: 0,
+        }
+    }
+
+    // idle clients with a full bucket and an expired budget hold no state worth keeping
+    fn is_idle(&self,limits : &LimitsConfig) -> bool {
+        self.connections == 0
+            && (self.budget_bytes == 0 || self.budget_start.elapsed() >= BUDGET_WINDOW)
+            && (limits.requests_per_second <= 0.0 || self.last_refill.elapsed().as_secs_f64() * limits.requests_per_second >= limits.burst as f64)
+    }
+
+    fn take_token(&mut self,limits : &LimitsConfig) -> Result<(),u64> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * limits.requests_per_second;
+        self.tokens = (self.tokens + refill).min(limits.burst as f64);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return Err(((1.0 - self.tokens) / limits.requests_per_second).ceil() as u64)
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    fn check_budget(&mut self,limits : &LimitsConfig) -> Result<(),u64> {
+        let elapsed = self.budget_start.elapsed();
+        if elapsed >= BUDGET_WINDOW {
+            self.budget_start = Instant::now();
+            self.budget_bytes = 0;
+            return Ok(())
+        }
+        if self.budget_bytes >= limits.max_bytes_per_ip_per_hour {
+            return Err((BUDGET_WINDOW - elapsed).as_secs().max(1))
+        }
+        Ok(())
+    }
+}
+
+fn limits() -> Option<&'static LimitsConfig> {
+    SERVER_CONFIG.get().map(|config| &config.limits)
+}
+
+fn with_client<F,T>(client_ip : &str,limits : &LimitsConfig,action : F) -> T
+where
+    F: FnOnce(&mut ClientLimits) -> T
+{
+    let clients = CLIENTS.get_or_init(|| Mutex::new(Clients { by_ip: HashMap::new(), last_sweep: Instant::now() }));
+    let mut clients = clients.lock().unwrap();
+    if !clients.by_ip.contains_key(client_ip) && clients.last_sweep.elapsed() >= SWEEP_INTERVAL {
+        clients.by_ip.retain(|_,client| !client.is_idle(limits));
+        clients.last_sweep = Instant::now();
+    }
+    let client = clients.by_ip.entry(client_ip.to_string()).or_insert_with(|| ClientLimits::new(limits));
+    action(client)
+}
+
+/*open connection, released on drop*/
+pub struct ConnectionGuard {
+    // set when counted against the per ip limit
+    client_ip : Option<String>
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1,Ordering::Relaxed);
+        if let (Some(client_ip),Some(clients)) = (&self.client_ip,CLIENTS.get()) {
+            if let Some(client) = clients.lock().unwrap().by_ip.get_mut(client_ip) {
+                client.connections = client.connections.saturating_sub(1);
+            }
+        }
+    }
+}
+
+// Err with the seconds to wait (Retry-After) when the global or the per ip connection limit is reached
+pub fn acquire_connection(client_ip : &str) -> Result<ConnectionGuard,u64> {
+    let connections = CONNECTIONS.fetch_add(1,Ordering::Relaxed) + 1;
+    let mut guard = ConnectionGuard { client_ip: None };
+    let Some(limits) = limits() else {
+        return Ok(guard)
+    };
+    if limits.max_connections > 0 && connections > limits.max_connections {
+        return Err(CONNECTION_RETRY_AFTER)
+    }
+    // reverse proxies carry many clients over their connections
+    if limits.max_connections_per_ip > 0 && !is_trusted_proxy(client_ip) {
+        let accepted = with_client(client_ip,limits,|client| {
+            if client.connections >= limits.max_connections_per_ip {
+                return false
+            }
+            client.connections += 1;
+            true
+        });
+        if !accepted {
+            return Err(CONNECTION_RETRY_AFTER)
+        }
+        guard.client_ip = Some(client_ip.to_string());
+    }
+    Ok(guard)
+}
+
+pub fn open_connections() -> usize {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+// Err with the seconds to wait (Retry-After) when the client is over its request rate or traffic budget
+pub fn check_request(client_ip : &str,route : Option<&str>) -> Result<(),u64> {
+    let Some(limits) = limits() else {
+        return Ok(())
+    };
+    let check_rate = limits.requests_per_second > 0.0;
+    let check_budget = limits.max_bytes_per_ip_per_hour > 0 && matches!(route,Some("garbage" | "empty" | "ws"));
+    if !check_rate && !check_budget {
+        return Ok(())
+    }
+    with_client(client_ip,limits,|client| {
+        if check_budget {
+            client.check_budget(limits)?;
+        }
+        if check_rate {
+            client.take_token(limits)?;
+        }
+        Ok(())
+    })
+}
+
+/*download & upload bytes counted against the hourly budget*/
+pub fn record_traffic(client_ip : &str,bytes : u64) {
+    let Some(limits) = limits() else {
+        return
+    };
+    if limits.max_bytes_per_ip_per_hour == 0 || bytes == 0 {
+        return
+    }
+    with_client(client_ip,limits,|client| client.budget_bytes += bytes);
+}
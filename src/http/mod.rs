@@ -1,22 +1,58 @@
-use std::collections::HashMap;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use std::fs::File;
-use std::io::Read;
-use crate::config::{DEF_ASSETS, SERVER_CONFIG};
+use crate::config::{relative_base_url, SpeedtestServer, SERVER_CONFIG};
+use crate::http::garbage::DownloadLimit;
+use crate::http::params::Params;
 
 pub mod http_server;
 mod routes;
 pub mod request;
 pub mod response;
 pub mod cookie;
+pub mod cors;
 pub mod tls;
 pub mod http_client;
+pub mod http2;
 mod tcp_socket;
+pub mod websocket;
+pub mod assets;
+pub mod compression;
+pub mod garbage;
+pub mod limiter;
+pub mod forwarded;
+pub mod proxy_protocol;
+pub mod acme;
+pub mod params;
+pub mod drain;
+#[cfg(unix)]
+mod upgrade;
 
-#[derive(Debug)]
+// bytes requested by one unit of `ckSize`
+const CK_SIZE_UNIT : u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Method {
     Get,
     Post,
+    Options,
+    Head,
+    Put,
+    Delete,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Options => "OPTIONS",
+            Method::Head => "HEAD",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Other(method) => method,
+        }
+    }
 }
 
 pub trait MethodStr {
@@ -28,47 +64,40 @@ impl MethodStr for str {
         match self {
             "GET" => Method::Get,
             "POST" => Method::Post,
-            _ => Method::Get
+            "OPTIONS" => Method::Options,
+            "HEAD" => Method::Head,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            _ => Method::Other(self.to_string())
         }
     }
 }
 
-pub async fn find_remote_ip_addr (conn: &mut TcpStream) -> String {
-    let client_addr = conn.peer_addr().unwrap();
-    client_addr.ip().to_string().replace("::ffff:","")
+// `GET, HEAD, OPTIONS` for Allow & Access-Control-Allow-Methods headers
+pub fn join_methods(methods : &[Method]) -> String {
+    methods.iter().map(|method| method.as_str()).collect::<Vec<&str>>().join(", ")
 }
 
-pub fn get_index_file_content(file_name : &str) -> Option<Vec<u8>> {
-    if SERVER_CONFIG.get()?.assets_path.is_empty() {
-        if file_name.contains("servers_list.js") {
-            Some(generate_server_endpoint())
-        } else {
-            let file_name = &file_name[1..];
-            let file = DEF_ASSETS.get_file(file_name)?;
-            Some(Vec::from(file.contents()))
-        }
-    } else {
-        let file_path = format!("{}{}",SERVER_CONFIG.get()?.assets_path,file_name);
-        if let Ok(mut file) = File::open(file_path) {
-            let mut file_bytes = Vec::new();
-            if file.read_to_end(&mut file_bytes).is_ok() {
-                Some(file_bytes)
-            } else {
-                None
-            }
-        } else {
-            None
+// with PROXY protocol the client address is read from the header preceding the connection data
+pub async fn find_remote_ip_addr (conn: &mut TcpStream,proxy_protocol : bool) -> std::io::Result<String> {
+    let mut client_addr = conn.peer_addr()?.ip();
+    if proxy_protocol {
+        if let Some(source_addr) = proxy_protocol::read_header(conn).await? {
+            client_addr = source_addr;
         }
     }
+    Ok(client_addr.to_canonical().to_string())
 }
 
-fn generate_server_endpoint() -> Vec<u8> {
-    let base_url = SERVER_CONFIG.get().unwrap().base_url.clone();
-    let base_url = if base_url.is_empty() {
-        "".to_string()
-    } else {
-        format!("{}/",&base_url[1..])
-    };
+pub(crate) fn generate_server_endpoint() -> Vec<u8> {
+    let config = SERVER_CONFIG.get().unwrap();
+    if !config.servers.is_empty() {
+        let endpoint = format!(r#"function get_servers() {{
+        return {};
+    }}"#,generate_server_list_json(None));
+        return Vec::from(endpoint.as_bytes())
+    }
+    let base_url = relative_base_url(&config.base_url);
     let endpoint = format!(r#"function get_servers() {{
         return [
             {{
@@ -84,20 +113,60 @@ fn generate_server_endpoint() -> Vec<u8> {
     Vec::from(endpoint.as_bytes())
 }
 
+// host : Host header of the request, the server of the default entry like `window.location.origin` in servers_list.js
+pub fn generate_server_list_json(host : Option<&str>) -> String {
+    let config = SERVER_CONFIG.get().unwrap();
+    server_list_json(&config.servers,&config.base_url,host)
+}
 
-pub fn get_chunk_count (query_params : &HashMap<String,String>) -> i32 {
-    let mut chunks = 4;
-    if let Some(ck_size) = query_params.get("ckSize") {
-        if let Ok(parsed_ck_size) = ck_size.parse::<i32>() {
-            if parsed_ck_size > 1024 {
-                chunks = 1024
-            } else {
-                chunks = parsed_ck_size
-            }
-        }
+fn server_list_json(servers : &[SpeedtestServer],base_url : &str,host : Option<&str>) -> String {
+    if !servers.is_empty() {
+        return serde_json::to_string(servers).unwrap_or("[]".to_string())
+    }
+    let base_url = relative_base_url(base_url);
+    let server = SpeedtestServer {
+        id: 1,
+        name: "Simple Server".to_string(),
+        server: host.map(|host| format!("//{host}/")).unwrap_or_default(),
+        dl_url: format!("{base_url}garbage"),
+        ul_url: format!("{base_url}empty"),
+        ping_url: format!("{base_url}empty"),
+        get_ip_url: format!("{base_url}getIP"),
+        sponsor_name: None,
+        sponsor_url: None,
+        location: None
+    };
+    serde_json::to_string(&[server]).unwrap_or("[]".to_string())
+}
+
+
+// a repeated parameter must keep one value, a cache or proxy in front may read another one than the server
+fn is_unambiguous(values : &[String]) -> bool {
+    values.windows(2).all(|pair| pair[0] == pair[1])
+}
+
+// `ckSize` download volume in MiB (default 4) or `duration` in seconds, None when invalid
+pub fn get_download_limit (query_params : &Params) -> Option<DownloadLimit> {
+    let config = SERVER_CONFIG.get().unwrap();
+    let (durations,ck_sizes) = (query_params.get_all("duration"),query_params.get_all("ckSize"));
+    if !is_unambiguous(durations) || !is_unambiguous(ck_sizes) {
+        return None
     }
-    chunks *= 2;
-    chunks
+    if let Some(duration) = durations.first() {
+        let seconds = duration.parse::<u64>().ok().filter(|seconds| *seconds > 0)?;
+        return Some(DownloadLimit {
+            bytes: config.max_download_bytes,
+            duration: Some(Duration::from_secs(seconds.min(config.max_download_duration))),
+        })
+    }
+    let ck_size = match ck_sizes.first() {
+        Some(ck_size) => ck_size.parse::<u64>().ok().filter(|ck_size| *ck_size > 0)?,
+        None => 4
+    };
+    Some(DownloadLimit {
+        bytes: ck_size.saturating_mul(CK_SIZE_UNIT).min(config.max_download_bytes),
+        duration: None,
+    })
 }
 
 #[macro_export]
@@ -109,3 +178,41 @@ macro_rules! make_route {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use super::*;
+
+    #[test]
+    fn empty_server_list_returns_this_server() {
+        let servers : Value = serde_json::from_str(&server_list_json(&[],"/backend",Some("speed.example.org:8080"))).unwrap();
+        assert_eq!(servers,serde_json::json!([{
+            "id": 1,
+            "name": "Simple Server",
+            "server": "//speed.example.org:8080/",
+            "dlURL": "backend/garbage",
+            "ulURL": "backend/empty",
+            "pingURL": "backend/empty",
+            "getIpURL": "backend/getIP"
+        }]));
+    }
+
+    #[test]
+    fn configured_servers_are_returned_as_is() {
+        let mut server : SpeedtestServer = serde_json::from_value(serde_json::json!({"name": "Amsterdam", "server": "//ams.example.com/"})).unwrap();
+        server.id = 7;
+        let servers : Value = serde_json::from_str(&server_list_json(&[server],"",None)).unwrap();
+        assert_eq!(servers[0]["id"],7);
+        assert_eq!(servers[0]["server"],"//ams.example.com/");
+        assert_eq!(servers.as_array().unwrap().len(),1);
+    }
+
+    #[test]
+    fn conflicting_repeated_download_parameters_are_ambiguous() {
+        let params = Params::parse_urlencoded(b"ckSize=4&duration=10&ckSize=4&duration=1000");
+        assert!(is_unambiguous(params.get_all("ckSize")));
+        assert!(!is_unambiguous(params.get_all("duration")));
+        assert!(is_unambiguous(params.get_all("missing")));
+    }
+}
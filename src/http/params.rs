@@ -0,0 +1,447 @@
+use std::fmt::Write;
+use case_insensitive_hashmap::CaseInsensitiveHashMap as CIHashMap;
+
+/*Query string & form fields, decoded, in request order, repeated keys keep every value*/
+
+#[derive(Debug, Default, Clone)]
+pub struct Params {
+    // values of every key, keys in order of first appearance
+    keys : Vec<(String,Vec<String>)>,
+    // (key index, value index) in request order
+    order : Vec<(usize,usize)>
+}
+
+impl Params {
+    pub fn insert(&mut self,key : String,value : String) {
+        let key_index = match self.keys.iter().position(|(name,_)| *name == key) {
+            Some(key_index) => key_index,
+            None => {
+                self.keys.push((key,Vec::new()));
+                self.keys.len() - 1
+            }
+        };
+        let values = &mut self.keys[key_index].1;
+        values.push(value);
+        self.order.push((key_index,values.len() - 1));
+    }
+
+    // first value of the key
+    pub fn get(&self,key : &str) -> Option<&String> {
+        self.get_all(key).first()
+    }
+
+    // every value of a repeated key, in request order
+    pub fn get_all(&self,key : &str) -> &[String] {
+        self.keys.iter().find(|(name,_)| name == key).map(|(_,values)| values.as_slice()).unwrap_or_default()
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&String,&String)> {
+        self.order.iter().map(|(key_index,value_index)| {
+            let (key,values) = &self.keys[*key_index];
+            (key,&values[*value_index])
+        })
+    }
+
+    /*`application/x-www-form-urlencoded` : `+` is a space, malformed escapes are kept as is*/
+    pub fn parse_urlencoded(input : &[u8]) -> Self {
+        let mut params = Params::default();
+        for pair in input.split(|byte| *byte == b'&').filter(|pair| !pair.is_empty()) {
+            let (key,value) = match pair.iter().position(|byte| *byte == b'=') {
+                Some(index) => (&pair[..index],&pair[index + 1..]),
+                None => (pair,&[][..])
+            };
+            params.insert(form_decode(key),form_decode(value));
+        }
+        params
+    }
+
+    // encoded back to a query string, without the `?`
+    pub fn to_query_string(&self) -> String {
+        self.entries()
+            .map(|(key,value)| format!("{}={}",percent_encode(key),percent_encode(value)))
+            .collect::<Vec<String>>()
+            .join("&")
+    }
+}
+
+fn hex_value(byte : u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+// `%XX` escapes to raw bytes, None when an escape is malformed
+pub(crate) fn percent_decode(input : &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let high = bytes.get(index + 1).copied().and_then(hex_value)?;
+            let low = bytes.get(index + 2).copied().and_then(hex_value)?;
+            decoded.push(high << 4 | low);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    Some(decoded)
+}
+
+// lenient form decoding, invalid utf-8 is replaced
+fn form_decode(input : &[u8]) -> String {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut index = 0;
+    while index < input.len() {
+        match input[index] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let escape = (input.get(index + 1).copied().and_then(hex_value),input.get(index + 2).copied().and_then(hex_value));
+                if let (Some(high),Some(low)) = escape {
+                    decoded.push(high << 4 | low);
+                    index += 2;
+                } else {
+                    decoded.push(b'%');
+                }
+            }
+            byte => decoded.push(byte)
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// RFC 3986 unreserved characters are kept
+pub fn percent_encode(input : &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte,b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded,"%{:02X}",byte);
+        }
+    }
+    encoded
+}
+
+/*one part of a multipart/form-data body*/
+#[derive(Debug)]
+pub struct FormPart {
+    pub name : String,
+    // set for file fields
+    pub filename : Option<String>,
+    pub data : Vec<u8>
+}
+
+// `boundary=abc` or `boundary="a b"` parameter of a multipart Content-Type
+pub fn multipart_boundary(content_type : &str) -> Option<String> {
+    let (_,parameters) = split_header_value(content_type);
+    parameters.into_iter()
+        .find(|(name,_)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_,boundary)| boundary)
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+fn find(haystack : &[u8],needle : &[u8],from : usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None
+    }
+    haystack[from..].windows(needle.len()).position(|window| window == needle).map(|index| index + from)
+}
+
+/*RFC 7578 body, binary safe : parts are split on `CRLF--boundary` and never decoded as a whole*/
+pub fn parse_multipart(boundary : &str,body : &[u8]) -> Vec<FormPart> {
+    let delimiter = format!("--{boundary}").into_bytes();
+    let separator = format!("\r\n--{boundary}").into_bytes();
+    let mut parts = Vec::new();
+    // preamble before the first delimiter is ignored
+    let Some(start) = find(body,&delimiter,0) else {
+        return parts
+    };
+    let mut position = start + delimiter.len();
+    loop {
+        // close delimiter `--boundary--`
+        if body[position..].starts_with(b"--") {
+            break;
+        }
+        // transport padding then CRLF after the delimiter
+        while body.get(position).is_some_and(|byte| *byte == b' ' || *byte == b'\t') {
+            position += 1;
+        }
+        if !body[position..].starts_with(b"\r\n") {
+            break;
+        }
+        position += 2;
+        let Some(end) = find(body,&separator,position) else {
+            break;
+        };
+        if let Some(part) = parse_part(&body[position..end]) {
+            parts.push(part);
+        }
+        position = end + separator.len();
+    }
+    parts
+}
+
+fn parse_part(part : &[u8]) -> Option<FormPart> {
+    // headers end with an empty line, a part may have none
+    let (head,data) = if part.starts_with(b"\r\n") {
+        (&[][..],&part[2..])
+    } else {
+        let end = find(part,b"\r\n\r\n",0)?;
+        (&part[..end],&part[end + 4..])
+    };
+    let mut headers = CIHashMap::new();
+    for line in String::from_utf8_lossy(head).split("\r\n") {
+        if let Some((name,value)) = line.split_once(':') {
+            headers.insert(name.trim().to_string(),value.trim().to_string());
+        }
+    }
+    let (disposition,parameters) = split_header_value(headers.get("Content-Disposition")?);
+    if !disposition.eq_ignore_ascii_case("form-data") {
+        return None
+    }
+    let parameter = |key : &str| parameters.iter().find(|(name,_)| name.eq_ignore_ascii_case(key)).map(|(_,value)| value.clone());
+    let name = parameter("name")?;
+    // RFC 5987 `filename*=UTF-8''a%20b.txt` takes precedence
+    let filename = parameter("filename*")
+        .and_then(|filename| {
+            let (charset,encoded) = filename.split_once("''")?;
+            charset.eq_ignore_ascii_case("utf-8").then(|| percent_decode(encoded)).flatten()
+        })
+        .and_then(|filename| String::from_utf8(filename).ok())
+        .or_else(|| parameter("filename"));
+    Some(FormPart {
+        name,
+        filename,
+        data: data.to_vec()
+    })
+}
+
+/*`value; key=token; key="quoted \" string"` into the value & its parameters*/
+fn split_header_value(header : &str) -> (String,Vec<(String,String)>) {
+    let mut chars = header.chars().peekable();
+    let value = chars.by_ref().take_while(|c| *c != ';').collect::<String>().trim().to_string();
+    let mut parameters = Vec::new();
+    loop {
+        let name = chars.by_ref().take_while(|c| *c != '=').collect::<String>().trim().to_string();
+        if name.is_empty() {
+            break;
+        }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut parameter = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => parameter.extend(chars.next()),
+                    '"' => break,
+                    c => parameter.push(c)
+                }
+            }
+            // rest up to the next parameter
+            chars.by_ref().take_while(|c| *c != ';').for_each(drop);
+        } else {
+            parameter = chars.by_ref().take_while(|c| *c != ';').collect::<String>().trim().to_string();
+        }
+        parameters.push((name,parameter));
+    }
+    (value,parameters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // deterministic generator, every run checks the same inputs
+    struct Fuzz(u64);
+
+    impl Fuzz {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            z ^ (z >> 31)
+        }
+
+        fn below(&mut self,bound : usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+
+        fn bytes(&mut self,max_len : usize,alphabet : &[u8]) -> Vec<u8> {
+            let len = self.below(max_len + 1);
+            (0..len).map(|_| alphabet[self.below(alphabet.len())]).collect()
+        }
+
+        fn text(&mut self,max_len : usize) -> String {
+            const CHARS : &[char] = &['a','Z','0','-','_','.','~',' ','+','%','&','=','?','/','#',';','é','€','日','\n'];
+            let len = self.below(max_len + 1);
+            (0..len).map(|_| CHARS[self.below(CHARS.len())]).collect()
+        }
+    }
+
+    const ALL_BYTES : &[u8] = &{
+        let mut bytes = [0u8;256];
+        let mut index = 0;
+        while index < 256 {
+            bytes[index] = index as u8;
+            index += 1;
+        }
+        bytes
+    };
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        let mut fuzz = Fuzz(1);
+        for _ in 0..2000 {
+            let text = fuzz.text(24);
+            let encoded = percent_encode(&text);
+            assert!(encoded.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-._~%".contains(&byte)));
+            assert_eq!(percent_decode(&encoded),Some(text.clone().into_bytes()));
+            assert_eq!(form_decode(encoded.as_bytes()),text);
+        }
+    }
+
+    #[test]
+    fn query_strings_round_trip_with_repeated_keys() {
+        let mut fuzz = Fuzz(2);
+        for _ in 0..1000 {
+            let mut params = Params::default();
+            let keys = ["a","ckSize","a b","k=v"];
+            for _ in 0..fuzz.below(6) {
+                params.insert(keys[fuzz.below(keys.len())].to_string(),fuzz.text(12));
+            }
+            let parsed = Params::parse_urlencoded(params.to_query_string().as_bytes());
+            assert!(parsed.entries().eq(params.entries()));
+        }
+    }
+
+    #[test]
+    fn repeated_keys_keep_every_value_in_order() {
+        let params = Params::parse_urlencoded(b"id=1&x=&id=2&id");
+        assert_eq!(params.get("id").map(String::as_str),Some("1"));
+        assert_eq!(params.get_all("id"),["1","2",""]);
+        assert_eq!(params.get_all("x"),[""]);
+        assert!(params.get_all("missing").is_empty());
+        assert_eq!(params.to_query_string(),"id=1&x=&id=2&id=");
+    }
+
+    #[test]
+    fn plus_is_a_space_only_in_forms() {
+        assert_eq!(form_decode(b"a+b%2Bc"),"a b+c");
+        assert_eq!(percent_decode("a+b%2Bc"),Some(b"a+b+c".to_vec()));
+        let params = Params::parse_urlencoded(b"q=1+1%3D2&a+b=c");
+        assert_eq!(params.get("q").map(String::as_str),Some("1 1=2"));
+        assert_eq!(params.get("a b").map(String::as_str),Some("c"));
+    }
+
+    #[test]
+    fn malformed_escapes() {
+        for input in ["%","%4","%zz","100%","%%41","%G0"] {
+            assert_eq!(percent_decode(input),None,"{input}");
+        }
+        assert_eq!(form_decode(b"%"),"%");
+        assert_eq!(form_decode(b"%4"),"%4");
+        assert_eq!(form_decode(b"%zz"),"%zz");
+        assert_eq!(form_decode(b"100%"),"100%");
+        assert_eq!(form_decode(b"%%41"),"%A");
+        assert_eq!(form_decode(b"%ff"),"\u{FFFD}");
+    }
+
+    #[test]
+    fn decoders_accept_any_input() {
+        let mut fuzz = Fuzz(3);
+        for _ in 0..5000 {
+            // escape heavy inputs, then any byte
+            let alphabet : &[u8] = if fuzz.below(2) == 0 { b"%+&=aF09z\xff\0" } else { ALL_BYTES };
+            let input = fuzz.bytes(32,alphabet);
+            let decoded = form_decode(&input);
+            assert!(decoded.chars().count() <= input.len());
+            if let Ok(text) = std::str::from_utf8(&input) {
+                if let Some(decoded) = percent_decode(text) {
+                    assert!(decoded.len() <= input.len());
+                }
+            }
+            let _ = Params::parse_urlencoded(&input);
+        }
+    }
+
+    fn multipart_body(boundary : &str,parts : &[(&str,Option<&str>,Vec<u8>)]) -> Vec<u8> {
+        let mut body = b"preamble\r\n".to_vec();
+        for (name,filename,data) in parts {
+            body.extend(format!("--{boundary}\r\n").as_bytes());
+            match filename {
+                Some(filename) => body.extend(format!("Content-Disposition: form-data; name=\"{name}\"; filename=\"{filename}\"\r\nContent-Type: application/octet-stream\r\n\r\n").as_bytes()),
+                None => body.extend(format!("Content-Disposition: form-data; name=\"{name}\"\r\n\r\n").as_bytes())
+            }
+            body.extend(data);
+            body.extend(b"\r\n");
+        }
+        body.extend(format!("--{boundary}--\r\nepilogue").as_bytes());
+        body
+    }
+
+    #[test]
+    fn multipart_parts_are_binary_safe() {
+        let boundary = "XyZ123";
+        // CRLF, bare delimiters & truncated separators inside the data are not part boundaries
+        let tricky = b"\r\n\r\nx--XyZ123 not at line start\r\n--XyZ12\r\n-XyZ123\r\n\0\xff\xfe--".to_vec();
+        let body = multipart_body(boundary,&[("dl",None,b"93.5".to_vec()),("file",Some("a.bin"),tricky.clone()),("empty",None,Vec::new())]);
+        let parts = parse_multipart(boundary,&body);
+        assert_eq!(parts.len(),3);
+        assert_eq!((parts[0].name.as_str(),parts[0].filename.as_deref(),parts[0].data.as_slice()),("dl",None,&b"93.5"[..]));
+        assert_eq!((parts[1].name.as_str(),parts[1].filename.as_deref()),("file",Some("a.bin")));
+        assert_eq!(parts[1].data,tricky);
+        assert!(parts[2].data.is_empty());
+    }
+
+    #[test]
+    fn multipart_random_parts_round_trip() {
+        let mut fuzz = Fuzz(4);
+        let boundary = "----fuzzboundary";
+        let separator = format!("\r\n--{boundary}").into_bytes();
+        for _ in 0..500 {
+            let parts = (0..fuzz.below(5)).map(|index| {
+                let mut data = fuzz.bytes(64,b"\r\n-fuzzboundary\0\xff");
+                while data.windows(separator.len()).any(|window| window == separator.as_slice()) || data.ends_with(b"\r") {
+                    data = fuzz.bytes(64,b"\r\n-fuzzboundary\0\xff");
+                }
+                (format!("field{index}"),fuzz.below(2) == 0,data)
+            }).collect::<Vec<_>>();
+            let specs = parts.iter()
+                .map(|(name,file,data)| (name.as_str(),file.then_some("f.bin"),data.clone()))
+                .collect::<Vec<_>>();
+            let parsed = parse_multipart(boundary,&multipart_body(boundary,&specs));
+            assert_eq!(parsed.len(),parts.len());
+            for (part,(name,file,data)) in parsed.iter().zip(&parts) {
+                assert_eq!(&part.name,name);
+                assert_eq!(part.filename.is_some(),*file);
+                assert_eq!(&part.data,data);
+            }
+        }
+    }
+
+    #[test]
+    fn multipart_parser_accepts_any_input() {
+        let mut fuzz = Fuzz(5);
+        for _ in 0..5000 {
+            let body = fuzz.bytes(96,b"\r\n-b: =;\"Content-Disposition form-data name x");
+            let _ = parse_multipart("b",&body);
+        }
+        assert!(parse_multipart("b",b"--b").is_empty());
+        assert!(parse_multipart("b",b"--b  ").is_empty());
+        assert!(parse_multipart("b",b"--b\r\n").is_empty());
+    }
+
+    #[test]
+    fn multipart_headers() {
+        assert_eq!(multipart_boundary("multipart/form-data; boundary=abc").as_deref(),Some("abc"));
+        assert_eq!(multipart_boundary("multipart/form-data; BOUNDARY=\"a b;c\"").as_deref(),Some("a b;c"));
+        assert_eq!(multipart_boundary("multipart/form-data; boundary="),None);
+        let body = b"--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"x\"; filename*=UTF-8''%E2%82%AC.txt\r\n\r\nv\r\n--b--";
+        let parts = parse_multipart("b",body);
+        assert_eq!(parts[0].filename.as_deref(),Some("€.txt"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use crate::http::request::{request_limits, with_timeout};
+
+/*HAProxy PROXY protocol v1 (text) & v2 (binary) header sent by L4 load balancers before any data*/
+
+const V2_SIGNATURE : [u8;12] = [0x0D,0x0A,0x0D,0x0A,0x00,0x0D,0x0A,0x51,0x55,0x49,0x54,0x0A];
+// `PROXY TCP6 <39> <39> <5> <5>\r\n`
+const V1_MAX_LENGTH : usize = 107;
+const V2_MAX_LENGTH : usize = 16 + 65535;
+
+fn invalid(message : &str) -> Error {
+    Error::new(ErrorKind::InvalidData,format!("Invalid PROXY protocol header : {message}"))
+}
+
+// source address of the proxied client, None for health checks (LOCAL / UNKNOWN) that carry no address
+pub async fn read_header(stream : &mut TcpStream) -> std::io::Result<Option<IpAddr>> {
+    match with_timeout(request_limits().header_timeout,read_header_inner(stream)).await {
+        Some(result) => result,
+        None => Err(Error::new(ErrorKind::TimedOut,"PROXY protocol header timeout"))
+    }
+}
+
+// read exactly the header, the rest of the stream belongs to TLS or HTTP
+async fn read_header_inner(stream : &mut TcpStream) -> std::io::Result<Option<IpAddr>> {
+    let mut prefix = [0u8;12];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V2_SIGNATURE {
+        return read_v2(stream).await
+    }
+    if !prefix.starts_with(b"PROXY ") {
+        return Err(invalid("missing signature"))
+    }
+    let line = read_v1_line(stream,prefix.to_vec()).await?;
+    parse_v1(&line[..line.len() - 2])
+}
+
+// the line is peeked from the socket buffer & only the bytes up to its CRLF are consumed
+async fn read_v1_line(stream : &mut TcpStream,mut line : Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let mut buffer = [0u8;V1_MAX_LENGTH];
+    loop {
+        let available = stream.peek(&mut buffer[..V1_MAX_LENGTH - line.len()]).await?;
+        if available == 0 {
+            return Err(Error::from(ErrorKind::UnexpectedEof))
+        }
+        // the CRLF may be split between two reads
+        let end = if line.ends_with(b"\r") && buffer[0] == b'\n' {
+            Some(1)
+        } else {
+            buffer[..available].windows(2).position(|pair| pair == b"\r\n").map(|position| position + 2)
+        };
+        let length = end.unwrap_or(available);
+        stream.read_exact(&mut buffer[..length]).await?;
+        line.extend_from_slice(&buffer[..length]);
+        if end.is_some() {
+            return Ok(line)
+        }
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("v1 line too long"))
+        }
+    }
+}
+
+// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443`
+fn parse_v1(line : &[u8]) -> std::io::Result<Option<IpAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 not ascii"))?;
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some(protocol @ ("TCP4" | "TCP6")) => {
+            let source = parts.next().and_then(|ip| ip.parse::<IpAddr>().ok()).ok_or_else(|| invalid("v1 source address"))?;
+            let destination = parts.next().and_then(|ip| ip.parse::<IpAddr>().ok()).ok_or_else(|| invalid("v1 destination address"))?;
+            let ports_valid = parts.by_ref().take(2).filter(|port| port.parse::<u16>().is_ok()).count() == 2;
+            if !ports_valid || parts.next().is_some() {
+                return Err(invalid("v1 ports"))
+            }
+            if (protocol == "TCP4") != (source.is_ipv4() && destination.is_ipv4()) {
+                return Err(invalid("v1 address family"))
+            }
+            Ok(Some(source))
+        }
+        _ => Err(invalid("v1 protocol"))
+    }
+}
+
+async fn read_v2<R>(stream : &mut R) -> std::io::Result<Option<IpAddr>>
+where
+    R: AsyncRead + Unpin
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await? as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("v2 version"))
+    }
+    if 16 + length > V2_MAX_LENGTH {
+        return Err(invalid("v2 length"))
+    }
+    // addresses are followed by TLVs which are not used
+    let mut payload = vec![0;length];
+    stream.read_exact(&mut payload).await?;
+    match version_command & 0x0F {
+        // LOCAL : connection opened by the proxy itself
+        0x0 => Ok(None),
+        0x1 => {
+            match family >> 4 {
+                0x1 => {
+                    let source : [u8;4] = payload.get(..4).and_then(|s| s.try_into().ok()).ok_or_else(|| invalid("v2 ipv4 length"))?;
+                    if payload.len() < 12 {
+                        return Err(invalid("v2 ipv4 length"))
+                    }
+                    Ok(Some(IpAddr::V4(Ipv4Addr::from(source))))
+                }
+                0x2 => {
+                    let source : [u8;16] = payload.get(..16).and_then(|s| s.try_into().ok()).ok_or_else(|| invalid("v2 ipv6 length"))?;
+                    if payload.len() < 36 {
+                        return Err(invalid("v2 ipv6 length"))
+                    }
+                    Ok(Some(IpAddr::V6(Ipv6Addr::from(source))))
+                }
+                // AF_UNSPEC & AF_UNIX carry no ip address
+                _ => Ok(None)
+            }
+        }
+        _ => Err(invalid("v2 command"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use super::*;
+
+    // header sent in parts followed by the request, returns the address & what is left on the stream
+    fn read_sent(parts : &[&[u8]]) -> (std::io::Result<Option<IpAddr>>,Vec<u8>) {
+        let parts = parts.iter().map(|part| part.to_vec()).collect::<Vec<_>>();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (mut server,_) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                for part in parts {
+                    client.write_all(&part).await.unwrap();
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+            });
+            let source = read_header_inner(&mut server).await;
+            let mut rest = Vec::new();
+            let _ = server.read_to_end(&mut rest).await;
+            (source,rest)
+        })
+    }
+
+    #[test]
+    fn v1_header_leaves_the_request_on_the_stream() {
+        let (source,rest) = read_sent(&[b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n"]);
+        assert_eq!(source.unwrap(),Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(rest,b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn v1_header_split_between_segments() {
+        let (source,rest) = read_sent(&[b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r",b"\nGET"]);
+        assert_eq!(source.unwrap(),Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(rest,b"GET");
+    }
+
+    #[test]
+    fn v1_header_over_the_length_cap_is_rejected() {
+        let mut line = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443".to_vec();
+        line.resize(V1_MAX_LENGTH,b' ');
+        line.extend_from_slice(b"\r\n");
+        let (source,_) = read_sent(&[&line]);
+        assert!(source.is_err());
+    }
+}
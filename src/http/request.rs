@@ -1,21 +1,61 @@
-use std::collections::HashMap;
 use std::future::Future;
+use std::io::{Error, ErrorKind};
 use std::pin::Pin;
+use std::time::{Duration, Instant};
 use log::trace;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::select;
 use case_insensitive_hashmap::CaseInsensitiveHashMap as CIHashMap;
-use crate::config::GARBAGE_DATA;
-use crate::http::{Method, MethodStr};
+use std::sync::OnceLock;
+use crate::config::{RequestLimitsConfig, ROUTES, SERVER_CONFIG};
+use crate::http::{drain, forwarded, limiter, websocket, Method, MethodStr};
+use crate::http::params::{multipart_boundary, parse_multipart, Params};
 use crate::http::response::Response;
+use crate::http::tls::requires_client_cert;
+use crate::results::metrics;
 
 #[derive(Debug)]
 pub struct Request {
     pub path: String,
     pub method: Method,
     pub remote_addr : String,
-    pub query_params: HashMap<String, String>,
+    // subject of the verified tls client certificate
+    pub client_subject : Option<String>,
+    pub query_params: Params,
     pub headers: CIHashMap<String>,
-    pub form_data : HashMap<String, String>
+    pub form_data : Params,
+    pub body_stats : BodyStats
+}
+
+/*bytes and time spent receiving the request body, measured on server side*/
+#[derive(Debug, Clone, Copy)]
+pub struct BodyStats {
+    pub bytes : u64,
+    pub start : Instant,
+    pub duration : Duration,
+    pub aborted : bool
+}
+
+impl Default for BodyStats {
+    fn default() -> Self {
+        BodyStats {
+            bytes: 0,
+            start: Instant::now(),
+            duration: Duration::ZERO,
+            aborted: false,
+        }
+    }
+}
+
+impl BodyStats {
+    pub fn mbps(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes as f64 * 8.0) / secs / 1_000_000.0
+        } else {
+            0.0
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -26,117 +66,143 @@ enum BodyType {
     FormUrlEncoded
 }
 
-pub async fn handle_socket<R,W,F>(remote_addr : &str,buf_reader: &mut BufReader<R>,buf_writer : &mut BufWriter<W>,result : F)
+pub async fn handle_socket<R,W,F>(remote_addr : &str,client_subject : Option<&str>,buf_reader: &mut BufReader<R>,buf_writer : &mut BufWriter<W>,result : F)
     where
 R: AsyncReadExt + Unpin,
 W: AsyncWriteExt + Unpin,
 F: Send + Sync + Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>>
 {
+    let limits = request_limits();
     'root_loop:loop {
-        //read status line
-        let parsed_status = {
-            if let Ok(Some(status_line)) = buf_reader.lines().next_line().await {
-                let status_lower = status_line.to_lowercase();
-                if check_is_status_line(status_lower) {
-                    parse_request_status_line(status_line)
-                } else {
-                    break 'root_loop;
+        //idle keep-alive connection waiting for its next request, closed when the server drains
+        let next_request = select! {
+            next_request = with_timeout(limits.keep_alive_timeout,buf_reader.fill_buf()) => next_request,
+            _ = drain::drain_started() => None
+        };
+        match next_request {
+            Some(Ok(buffer)) if !buffer.is_empty() => {}
+            _ => break 'root_loop
+        }
+        //read status line & headers
+        let (parsed_status,parsed_headers) = match with_timeout(limits.header_timeout,read_request_head(buf_reader,limits)).await {
+            Some(Ok(head)) => head,
+            Some(Err(head_error)) => {
+                let response = match head_error {
+                    HeadError::RequestLineTooLong => Response::res_414(),
+                    HeadError::HeadersTooLarge => Response::res_431(),
+                    HeadError::ConflictingFraming => {
+                        let mut response = Response::res_400();
+                        response.set_header("Connection","close");
+                        response
+                    }
+                    HeadError::Invalid => break 'root_loop
+                };
+                metrics::record_request("unknown",response.status);
+                if let Err(e) = response.write_http1(remote_addr,buf_writer).await {
+                    trace!("Error socket write : {e}");
                 }
-            } else {
+                break 'root_loop;
+            }
+            None => {
+                trace!("Request head timeout, closing connection");
                 break 'root_loop;
             }
         };
-        //read headers
-        let parsed_headers = header_parser(buf_reader).await;
+        //websocket test transport takes over the connection
+        if is_websocket_route(&parsed_status.1) && websocket::is_upgrade_request(&parsed_headers) {
+            let remote_addr = forwarded::client_addr(&parsed_headers,remote_addr);
+            let refused = if let Err(retry_after) = limiter::check_request(&remote_addr,Some("ws")) {
+                Some(Response::res_429(retry_after))
+            } else if requires_client_cert(Some("ws")) && client_subject.is_none() {
+                Some(Response::res_403())
+            } else {
+                None
+            };
+            if let Some(response) = refused {
+                metrics::record_request("ws",response.status);
+                if let Err(e) = response.write_http1(&remote_addr,buf_writer).await {
+                    trace!("Error socket write : {e}");
+                }
+                break 'root_loop;
+            }
+            websocket::handle_websocket(&remote_addr,&parsed_headers,buf_reader,buf_writer).await;
+            break 'root_loop;
+        }
         //read body content
+        let mut body_stats = BodyStats::default();
         let body_form_data = {
-            let (body_type,body_size) = check_has_body(&parsed_headers);
-            match body_type {
+            let Some((body_type,body_size)) = check_has_body(&parsed_headers) else {
+                // the unread body can't be skipped, the connection is closed after it
+                let mut response = Response::res_400();
+                response.set_header("Connection","close");
+                metrics::record_request(metrics::route_name(&parsed_status.1),response.status);
+                if let Err(e) = response.write_http1(remote_addr,buf_writer).await {
+                    trace!("Error socket write : {e}");
+                }
+                break 'root_loop;
+            };
+            if matches!(body_type,Some(BodyType::Form | BodyType::FormUrlEncoded)) && body_size.unwrap_or(0) > limits.max_form_body_size {
+                let response = Response::res_413();
+                metrics::record_request(metrics::route_name(&parsed_status.1),response.status);
+                if let Err(e) = response.write_http1(remote_addr,buf_writer).await {
+                    trace!("Error socket write : {e}");
+                }
+                break 'root_loop;
+            }
+            if body_type.is_some() && parsed_headers.get("Expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue")) {
+                if let Err(e) = send_continue(buf_writer).await {
+                    trace!("Error socket write continue : {e}");
+                    break 'root_loop;
+                }
+            }
+            let body_start = Instant::now();
+            let body_form_data = match body_type {
                 Some(body_type) => {
                     match body_type {
                         BodyType::Fixed => {
-                            match body_size {
-                                Some(body_size) => {
-                                    let mut buffer = [0; 1024];
-                                    let mut len: usize = 0;
-                                    loop {
-                                        let bytes_read = buf_reader.read_exact(&mut buffer).await;
-                                        match bytes_read {
-                                            Ok(0) => {
-                                                buffer.fill(0);
-                                                break;
-                                            }
-                                            Ok(_) => {
-                                                len += buffer.len();
-                                                if len >= body_size as usize {
-                                                    buffer.fill(0);
-                                                    break;
-                                                }
-                                            }
-                                            Err(_) => {
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    None
-                                }
-                                None => {
-                                    None
-                                }
+                            if let Err(e) = discard_fixed_body(buf_reader,body_size.unwrap_or(0),&mut body_stats.bytes,limits).await {
+                                trace!("Error read fixed body : {e}");
+                                body_stats.aborted = true;
                             }
+                            None
                         }
                         BodyType::Chunked => {
-                            let mut buffer = [0; 1024];
-                            loop {
-                                let bytes_read = buf_reader.read_exact(&mut buffer).await;
-                                match bytes_read {
-                                    Ok(0) => {
-                                        buffer.fill(0);
-                                        break;
-                                    }
-                                    Ok(_) => {
-                                        buffer.fill(0);
-                                    }
-                                    Err(_) => {
-                                        break;
-                                    }
-                                }
+                            if let Err(e) = discard_chunked_body(buf_reader,&mut body_stats.bytes,limits).await {
+                                trace!("Error read chunked body : {e}");
+                                body_stats.aborted = true;
                             }
                             None
                         }
                         BodyType::Form => {
-                            let form_boundary = get_content_boundary(parsed_headers.get("Content-Type").unwrap());
-                            match form_boundary {
-                                Some(form_boundary) => {
-                                    match body_size {
-                                        Some(body_size) => {
-                                            let mut body = Vec::with_capacity(body_size as usize);
-                                            buf_reader.take(body_size).read_to_end(&mut body).await.unwrap();
-                                            let form_data = parse_form_data(&form_boundary,&body);
-                                            body.fill(0);
-                                            Some(form_data)
-                                        }
-                                        None => {
-                                            None
-                                        }
-                                    }
+                            let form_boundary = multipart_boundary(parsed_headers.get("Content-Type").unwrap());
+                            let body = read_fixed_body(buf_reader,body_size.unwrap_or(0),limits).await;
+                            match (form_boundary,body) {
+                                (Some(form_boundary),Ok(mut body)) => {
+                                    body_stats.bytes = body.len() as u64;
+                                    let form_data = parse_form_data(&form_boundary,&body);
+                                    body.fill(0);
+                                    Some(form_data)
                                 }
-                                None => {
+                                (None,Ok(_)) => {
                                     None
                                 }
+                                (_,Err(e)) => {
+                                    trace!("Error read form body : {e}");
+                                    break 'root_loop;
+                                }
                             }
                         }
                         BodyType::FormUrlEncoded => {
-                            match body_size {
-                                Some(body_size) => {
-                                    let mut body = Vec::with_capacity(body_size as usize);
-                                    buf_reader.take(body_size).read_to_end(&mut body).await.unwrap();
-                                    let form_data = parse_form_url_encoded(&body);
+                            match read_fixed_body(buf_reader,body_size.unwrap_or(0),limits).await {
+                                Ok(mut body) => {
+                                    body_stats.bytes = body.len() as u64;
+                                    let form_data = Params::parse_urlencoded(&body);
                                     body.fill(0);
                                     Some(form_data)
                                 }
-                                None => {
-                                    None
+                                Err(e) => {
+                                    trace!("Error read form body : {e}");
+                                    break 'root_loop;
                                 }
                             }
                         }
@@ -145,71 +211,279 @@ F: Send + Sync + Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>>
                 None => {
                     None
                 }
-            }
+            };
+            body_stats.start = body_start;
+            body_stats.duration = body_start.elapsed();
+            body_form_data
         };
         //trust proxy
-        let remote_addr = trust_addr_proxy(&parsed_headers,remote_addr);
+        let remote_addr = forwarded::client_addr(&parsed_headers,remote_addr);
         //gen request
-        let response = result(Request {
+        let mut response = result(Request {
             path: parsed_status.1,
             method: parsed_status.0,
-            remote_addr,
+            remote_addr : remote_addr.clone(),
+            client_subject : client_subject.map(str::to_string),
             query_params: parsed_status.2,
             headers: parsed_headers,
-            form_data : body_form_data.clone().unwrap_or(HashMap::new())
+            form_data : body_form_data.unwrap_or_default(),
+            body_stats
         }).await;
-        if let Err(e) = buf_writer.write_all(&response.data).await {
-            trace!("Error socket write : {e}")
+        //client went away in the middle of the body, the handler has seen what was received
+        if body_stats.aborted {
+            break 'root_loop;
         }
-        if response.chunk_count > 0 {
-            for _ in 0..response.chunk_count {
-                if let Err(e) = buf_writer.write_all(GARBAGE_DATA.get().unwrap()).await {
-                    trace!("Error socket write chunk : {e}")
-                }
-            }
-            if let Err(e) = buf_writer.write_all(b"0\r\n\r\n").await {
-                trace!("Error socket write eof : {e}")
-            }
+        //last response of the connection while draining or when the handler closes it
+        let draining = drain::is_draining();
+        if draining {
+            response.set_header("Connection","close");
+        }
+        let close = draining || response.header("Connection").is_some_and(|connection| connection.eq_ignore_ascii_case("close"));
+        if let Err(e) = response.write_http1(&remote_addr,buf_writer).await {
+            trace!("Error socket write : {e}");
+            break 'root_loop;
         }
-        if let Err(e) = buf_writer.flush().await {
-            trace!("Error socket flush : {e}")
+        if close {
+            break 'root_loop;
         }
     }
 }
 
-//allow http 1.* & POST, GET, OPTIONS methods
+//allow http 1.* & any method token, unsupported methods are answered by the router
 fn check_is_status_line (line : String) -> bool {
-    line.contains("http/1.") && (line.starts_with("get") || line.starts_with("options") || line.starts_with("post"))
+    let method = line.split(' ').next().unwrap_or("");
+    line.contains("http/1.") && !method.is_empty() && method.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+fn is_websocket_route(path : &str) -> bool {
+    ROUTES.get().and_then(|routes| routes.get(path.trim())).is_some_and(|route| *route == "ws")
 }
 
-#[allow(dead_code)]
 fn hex_string_to_int(hex_string: &str) -> Option<u64> {
     u64::from_str_radix(hex_string, 16).ok()
 }
 
-pub async fn header_parser<R>(buf_reader: &mut BufReader<R>) -> CIHashMap<String>
+async fn send_continue<W>(buf_writer : &mut BufWriter<W>) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin
+{
+    buf_writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    buf_writer.flush().await
+}
+
+pub(crate) fn request_limits() -> &'static RequestLimitsConfig {
+    static DEFAULT_LIMITS : OnceLock<RequestLimitsConfig> = OnceLock::new();
+    SERVER_CONFIG.get().map(|config| &config.request_limits).unwrap_or_else(|| DEFAULT_LIMITS.get_or_init(RequestLimitsConfig::default))
+}
+
+// None once the timeout in seconds elapsed, 0 waits forever
+pub(crate) async fn with_timeout<F>(seconds : u64,future : F) -> Option<F::Output>
+where
+    F: Future
+{
+    if seconds == 0 {
+        return Some(future.await)
+    }
+    tokio::time::timeout(Duration::from_secs(seconds),future).await.ok()
+}
+
+#[derive(Debug)]
+enum HeadError {
+    Invalid,
+    RequestLineTooLong,
+    HeadersTooLarge,
+    // repeated Content-Length or Transfer-Encoding with different values
+    ConflictingFraming
+}
+
+// line without its CRLF, Ok(None) on a closed connection, InvalidData only past max_length bytes
+async fn read_line_limited<R>(buf_reader : &mut BufReader<R>,max_length : usize) -> std::io::Result<Option<String>>
+where
+    R: AsyncReadExt + Unpin
+{
+    let mut line = Vec::new();
+    loop {
+        let buffer = buf_reader.fill_buf().await?;
+        if buffer.is_empty() {
+            if line.is_empty() {
+                return Ok(None)
+            }
+            return Err(Error::new(ErrorKind::UnexpectedEof,"line ended before LF"))
+        }
+        let (consumed,done) = match buffer.iter().position(|byte| *byte == b'\n') {
+            Some(end) => (end + 1,true),
+            None => (buffer.len(),false)
+        };
+        if line.len() + consumed > max_length + 2 {
+            return Err(Error::new(ErrorKind::InvalidData,"line too long"))
+        }
+        line.extend_from_slice(&buffer[..consumed]);
+        buf_reader.consume(consumed);
+        if done {
+            break;
+        }
+    }
+    while line.last().is_some_and(|byte| *byte == b'\n' || *byte == b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| Error::new(ErrorKind::InvalidInput,"line not utf-8"))
+}
+
+async fn read_request_head<R>(buf_reader : &mut BufReader<R>,limits : &RequestLimitsConfig) -> Result<((Method,String,Params),CIHashMap<String>),HeadError>
+where
+    R: AsyncReadExt + Unpin
+{
+    let status_line = match read_line_limited(buf_reader,limits.max_request_line).await {
+        Ok(Some(status_line)) => status_line,
+        Err(e) if e.kind() == ErrorKind::InvalidData => return Err(HeadError::RequestLineTooLong),
+        _ => return Err(HeadError::Invalid)
+    };
+    if !check_is_status_line(status_line.to_lowercase()) {
+        return Err(HeadError::Invalid)
+    }
+    let headers = read_headers(buf_reader,limits).await?;
+    Ok((parse_request_status_line(status_line),headers))
+}
+
+// header lines until the empty one, bounded in count & total size
+async fn read_headers<R>(buf_reader : &mut BufReader<R>,limits : &RequestLimitsConfig) -> Result<CIHashMap<String>,HeadError>
 where
     R: AsyncReadExt + Unpin
 {
     let mut headers_out = CIHashMap::new();
-    'header_loop:loop {
-        if let Ok(Some(header_line)) = buf_reader.lines().next_line().await {
-            if header_line.is_empty() {
-                break 'header_loop;
-            } else {
-                let mut header_parts = header_line.splitn(2, ':');
-                if let (Some(header_key),Some(header_val)) = (header_parts.next(),header_parts.next()) {
-                    headers_out.insert(header_key.trim().to_string(),header_val.trim().to_string());
-                }
+    let mut headers_size = 0;
+    let mut headers_count = 0;
+    loop {
+        let header_line = match read_line_limited(buf_reader,limits.max_header_size - headers_size).await {
+            Ok(Some(header_line)) => header_line,
+            Err(e) if e.kind() == ErrorKind::InvalidData => return Err(HeadError::HeadersTooLarge),
+            _ => return Err(HeadError::Invalid)
+        };
+        if header_line.is_empty() {
+            break;
+        }
+        headers_size += header_line.len();
+        headers_count += 1;
+        if headers_count > limits.max_header_count || headers_size >= limits.max_header_size {
+            return Err(HeadError::HeadersTooLarge)
+        }
+        let mut header_parts = header_line.splitn(2, ':');
+        if let (Some(header_key),Some(header_val)) = (header_parts.next(),header_parts.next()) {
+            let (header_key,header_val) = (header_key.trim(),header_val.trim());
+            // the body length must be the same whichever line a server or proxy reads
+            let is_framing = header_key.eq_ignore_ascii_case("Content-Length") || header_key.eq_ignore_ascii_case("Transfer-Encoding");
+            if is_framing && headers_out.get(header_key).is_some_and(|previous| previous != header_val) {
+                return Err(HeadError::ConflictingFraming)
             }
-        } else {
-            break 'header_loop;
+            headers_out.insert(header_key.to_string(),header_val.to_string());
+        }
+    }
+    Ok(headers_out)
+}
+
+// buffered body data, TimedOut when the client sends nothing for body_timeout
+pub(crate) async fn fill_body_buf<'a,R>(buf_reader : &'a mut BufReader<R>,limits : &RequestLimitsConfig) -> std::io::Result<&'a [u8]>
+where
+    R: AsyncReadExt + Unpin
+{
+    match with_timeout(limits.body_timeout,buf_reader.fill_buf()).await {
+        Some(buffer) => buffer,
+        None => Err(Error::new(ErrorKind::TimedOut,"body read timeout"))
+    }
+}
+
+//body readers, upload data is consumed straight from the read buffer without copying
+async fn discard_fixed_body<R>(buf_reader: &mut BufReader<R>,body_size : u64,received : &mut u64,limits : &RequestLimitsConfig) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin
+{
+    let mut remaining = body_size;
+    while remaining > 0 {
+        let available = fill_body_buf(buf_reader,limits).await?.len();
+        if available == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof,"body ended before content length"))
+        }
+        let consumed = available.min(remaining as usize);
+        buf_reader.consume(consumed);
+        remaining -= consumed as u64;
+        *received += consumed as u64;
+    }
+    Ok(())
+}
+
+// chunk size, terminator & trailer lines
+async fn read_chunk_line<R>(buf_reader: &mut BufReader<R>,limits : &RequestLimitsConfig) -> std::io::Result<String>
+where
+    R: AsyncReadExt + Unpin
+{
+    match with_timeout(limits.body_timeout,read_line_limited(buf_reader,limits.max_header_size)).await {
+        Some(line) => line?.ok_or(Error::new(ErrorKind::UnexpectedEof,"body ended before last chunk")),
+        None => Err(Error::new(ErrorKind::TimedOut,"body read timeout"))
+    }
+}
+
+async fn discard_chunked_body<R>(buf_reader: &mut BufReader<R>,received : &mut u64,limits : &RequestLimitsConfig) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin
+{
+    loop {
+        //chunk size line : size in hex with optional extensions
+        let line = read_chunk_line(buf_reader,limits).await?;
+        let size_part = line.split(';').next().unwrap_or("").trim();
+        let chunk_size = hex_string_to_int(size_part)
+            .ok_or(Error::new(ErrorKind::InvalidData,"invalid chunk size"))?;
+        if chunk_size == 0 {
+            break;
+        }
+        discard_fixed_body(buf_reader,chunk_size,received,limits).await?;
+        //chunk data terminator
+        if !read_chunk_line(buf_reader,limits).await?.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData,"invalid chunk terminator"))
+        }
+    }
+    //trailers until empty line
+    let mut trailers_size = 0;
+    loop {
+        let line = read_chunk_line(buf_reader,limits).await?;
+        if line.trim().is_empty() {
+            break;
+        }
+        trailers_size += line.len();
+        if trailers_size > limits.max_header_size {
+            return Err(Error::new(ErrorKind::InvalidData,"trailers too large"))
         }
     }
-    headers_out
+    Ok(())
 }
 
-fn check_has_body(headers : &CIHashMap<String>) -> (Option<BodyType>,Option<u64>) {
+// body_size is bounded by max_form_body_size
+async fn read_fixed_body<R>(buf_reader: &mut BufReader<R>,body_size : u64,limits : &RequestLimitsConfig) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncReadExt + Unpin
+{
+    let mut body = Vec::with_capacity(body_size as usize);
+    while (body.len() as u64) < body_size {
+        let buffer = fill_body_buf(buf_reader,limits).await?;
+        if buffer.is_empty() {
+            return Err(Error::new(ErrorKind::UnexpectedEof,"body ended before content length"))
+        }
+        let consumed = buffer.len().min((body_size - body.len() as u64) as usize);
+        body.extend_from_slice(&buffer[..consumed]);
+        buf_reader.consume(consumed);
+    }
+    Ok(body)
+}
+
+// response headers read by the http client, with the default request limits
+pub async fn header_parser<R>(buf_reader: &mut BufReader<R>) -> CIHashMap<String>
+where
+    R: AsyncReadExt + Unpin
+{
+    read_headers(buf_reader,&RequestLimitsConfig::default()).await.unwrap_or_default()
+}
+
+// None when the body length is ambiguous (Content-Length with Transfer-Encoding) or invalid, the connection can't be reused
+fn check_has_body(headers : &CIHashMap<String>) -> Option<(Option<BodyType>,Option<u64>)> {
     let content_type_form = if let Some(content_type) = headers.get("Content-Type") {
         if content_type.starts_with("multipart/form-data;") {
             Some(BodyType::Form)
@@ -221,28 +495,33 @@ fn check_has_body(headers : &CIHashMap<String>) -> (Option<BodyType>,Option<u64>
     } else {
         None
     };
+    if headers.get("Content-Length").is_some() && headers.get("Transfer-Encoding").is_some() {
+        return None
+    }
     //check fixed body
     if let Some(content_len) = headers.get("Content-Length") {
-        let content_len = content_len.parse::<u64>().unwrap_or(0);
+        let content_len = content_len.trim().parse::<u64>().ok()?;
         if content_len > 0 {
             let body_type = if let Some(content_type_form) = content_type_form {
                 content_type_form
             } else {
                 BodyType::Fixed
             };
-            return (Some(body_type),Some(content_len))
+            return Some((Some(body_type),Some(content_len)))
         };
     };
-    //check chunked body
+    //check chunked body, chunked must be the final coding
     if let Some(transfer_encoding) = headers.get("Transfer-Encoding") {
-        if transfer_encoding == "chunked" {
-            return (Some(BodyType::Chunked),Some(0))
+        let last_coding = transfer_encoding.rsplit(',').next().unwrap_or_default().trim();
+        if !last_coding.eq_ignore_ascii_case("chunked") {
+            return None
         }
+        return Some((Some(BodyType::Chunked),Some(0)))
     }
-    (None,None)
+    Some((None,None))
 }
 
-fn parse_request_status_line (line : String) -> (Method,String,HashMap<String, String>) {
+fn parse_request_status_line (line : String) -> (Method,String,Params) {
     let mut split_status = line.split(' ');
     let method_str = split_status.next().unwrap();
     let raw_path = split_status.next().unwrap();
@@ -250,23 +529,11 @@ fn parse_request_status_line (line : String) -> (Method,String,HashMap<String, S
     (method_str.to_method(),path.to_string(),query_params)
 }
 
-fn parse_raw_path(raw_path: &str) -> (&str, HashMap<String, String>) {
-    let mut real_path = raw_path;
-    let mut query_params = HashMap::new();
-    if raw_path.contains('?') {
-        let split_raw_path = raw_path.split('?');
-        let vec_path = split_raw_path.collect::<Vec<&str>>();
-        real_path = clear_path_end_slash(vec_path[0]);
-        let raw_query_params = vec_path[1];
-        let split_raw_query_params = raw_query_params.split('&');
-        for part in split_raw_query_params {
-            let mut split_part = part.split('=');
-            if let (Some(query_key),Some(query_val)) = (split_part.next(),split_part.next())  {
-                query_params.insert(query_key.to_string(), query_val.to_string());
-            }
-        }
+pub(crate) fn parse_raw_path(raw_path: &str) -> (&str, Params) {
+    match raw_path.split_once('?') {
+        Some((path,query)) => (clear_path_end_slash(path), Params::parse_urlencoded(query.as_bytes())),
+        None => (raw_path, Params::default())
     }
-    (real_path, query_params)
 }
 
 fn clear_path_end_slash(input: &str) -> &str {
@@ -277,72 +544,94 @@ fn clear_path_end_slash(input: &str) -> &str {
     }
 }
 
-fn trust_addr_proxy(headers : &CIHashMap<String>,remote_addr : &str) -> String {
-    headers.get("X-Real-IP")
-        .map(|s| s.as_str())
-        .or_else(|| {
-            headers.get("X-Forwarded-For").and_then(|s| {
-                s.split(',').next().map(|ip| ip.trim())
-            })
-        }).unwrap_or(remote_addr).to_string()
+//form-data-parser
+pub(crate) fn is_form_body(headers : &CIHashMap<String>) -> bool {
+    headers.get("Content-Type").is_some_and(|content_type| {
+        content_type.starts_with("multipart/form-data;") || content_type.starts_with("application/x-www-form-urlencoded")
+    })
 }
 
-//form-data-parser
-fn get_content_boundary(content_type : &str) -> Option<String> {
-    let parts = content_type.split(';');
-    let mut boundary = None;
-    for part in parts {
-        let part = part.trim();
-        if part.starts_with("boundary=") {
-            let mut boundary_str = part.splitn(2,'=');
-            let boundary_result = boundary_str.nth(1);
-            match boundary_result {
-                None => {},
-                Some(boundary_result) => {
-                    let mut boundary_p = "--".to_string();
-                    boundary_p.push_str(boundary_result);
-                    boundary = Some(boundary_p)
-                }
-            }
-            break;
-        }
+pub(crate) fn parse_form_body(headers : &CIHashMap<String>,body : &[u8]) -> Option<Params> {
+    let content_type = headers.get("Content-Type")?;
+    if content_type.starts_with("multipart/form-data;") {
+        let form_boundary = multipart_boundary(content_type)?;
+        Some(parse_form_data(&form_boundary,body))
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        Some(Params::parse_urlencoded(body))
+    } else {
+        None
     }
-    boundary
 }
 
-fn parse_form_data(boundary : &str,body : &[u8]) -> HashMap<String,String> {
-    let body_str = String::from_utf8_lossy(body);
-    let mut form_data = HashMap::new();
-    let form_parts = body_str.split(boundary);
-    for form_part in form_parts {
-        let form_part = form_part.trim();
-        if !form_part.is_empty() && form_part != "--" {
-            let mut body_parts = form_part.splitn(2,"\r\n");  //Content-Disposition: form-data; name="key"\r\nvalue
-            if let (Some(disposition),Some(value)) = (body_parts.next(),body_parts.next()) {
-                let value = value.replace("\r\n","");
-                //parse key
-                let mut split_disposition = disposition.splitn(2,';');
-                if let Some(name_part) = split_disposition.nth(1) {
-                    if let Some(key) = name_part.split_once('=').map(|x|x.1) {
-                        let key = key.replace('"',"");
-                        form_data.insert(key,value);
-                    }
-                }
-            }
-        }
+// text fields of a multipart body, file fields are left out
+fn parse_form_data(boundary : &str,body : &[u8]) -> Params {
+    let mut form_data = Params::default();
+    for part in parse_multipart(boundary,body).into_iter().filter(|part| part.filename.is_none()) {
+        form_data.insert(part.name,String::from_utf8_lossy(&part.data).into_owned());
     }
     form_data
 }
 
-fn parse_form_url_encoded(body : &[u8]) -> HashMap<String,String> {
-    let body_str = String::from_utf8_lossy(body);
-    let split_parts = body_str.split('&');
-    let mut form_data = HashMap::new();
-    for part in split_parts {
-        let mut split_key_value = part.splitn(2,'=');
-        if let (Some(key),Some(value)) = (split_key_value.next(),split_key_value.next()) {
-            form_data.insert(key.to_string(),value.to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body_of(headers : &[(&str,&str)]) -> Option<(Option<BodyType>,Option<u64>)> {
+        let mut map = CIHashMap::new();
+        for (name,value) in headers {
+            map.insert(name.to_string(),value.to_string());
         }
-    };
-    form_data
-}
\ No newline at end of file
+        check_has_body(&map)
+    }
+
+    #[test]
+    fn content_length_with_transfer_encoding_is_rejected() {
+        assert!(body_of(&[("Content-Length","10"),("Transfer-Encoding","chunked")]).is_none());
+        assert!(body_of(&[("content-length","0"),("transfer-encoding","identity")]).is_none());
+    }
+
+    #[test]
+    fn chunked_is_case_insensitive() {
+        for encoding in ["chunked","Chunked","CHUNKED","gzip, chunked"] {
+            assert!(matches!(body_of(&[("Transfer-Encoding",encoding)]),Some((Some(BodyType::Chunked),_))),"{encoding}");
+        }
+    }
+
+    #[test]
+    fn chunked_not_last_or_unknown_coding_is_rejected() {
+        assert!(body_of(&[("Transfer-Encoding","chunked, gzip")]).is_none());
+        assert!(body_of(&[("Transfer-Encoding","gzip")]).is_none());
+    }
+
+    fn read_head(head : &str) -> Result<CIHashMap<String>,HeadError> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut buf_reader = BufReader::new(head.as_bytes());
+        runtime.block_on(read_headers(&mut buf_reader,&RequestLimitsConfig::default()))
+    }
+
+    #[test]
+    fn conflicting_framing_headers_are_rejected() {
+        let conflicting = [
+            "Content-Length: 5\r\nContent-Length: 50\r\n\r\n",
+            "content-length: 5\r\nContent-Length: 50\r\n\r\n",
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n\r\n",
+        ];
+        for head in conflicting {
+            assert!(matches!(read_head(head),Err(HeadError::ConflictingFraming)),"{head}");
+        }
+    }
+
+    #[test]
+    fn repeated_identical_framing_headers_are_kept() {
+        let headers = read_head("Content-Length: 5\r\nContent-Length: 5\r\nAccept: a\r\nAccept: b\r\n\r\n").unwrap();
+        assert!(matches!(check_has_body(&headers),Some((Some(BodyType::Fixed),Some(5)))));
+    }
+
+    #[test]
+    fn invalid_content_length_is_rejected() {
+        assert!(body_of(&[("Content-Length","abc")]).is_none());
+        assert!(body_of(&[("Content-Length","-1")]).is_none());
+        assert!(matches!(body_of(&[("Content-Length","12")]),Some((Some(BodyType::Fixed),Some(12)))));
+        assert!(matches!(body_of(&[]),Some((None,None))));
+    }
+}
@@ -1,247 +1,329 @@
-use crate::http::get_index_file_content;
+use std::fmt;
+use std::fmt::Formatter;
+use std::time::Instant;
+use http::StatusCode;
+use tokio::io::{AsyncRead, AsyncWriteExt, BufWriter};
+use crate::config::SERVER_CONFIG;
+use crate::http::garbage::{DownloadLimit, GarbageSource};
+use crate::results::measure;
+
+const NO_CACHE : [(&str,&str);3] = [
+    ("Cache-Control","no-store, no-cache, must-revalidate, max-age=0, s-maxage=0"),
+    ("Cache-Control","post-check=0, pre-check=0"),
+    ("Pragma","no-cache")
+];
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // download payload, sent with chunked transfer encoding on HTTP/1.1
+    Garbage(DownloadLimit),
+    // reader with known content length
+    Stream(Box<dyn AsyncRead + Send + Unpin>,u64),
+}
+
+impl Body {
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Garbage(_) => None,
+            Body::Stream(_,len) => Some(*len),
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => write!(f,"Empty"),
+            Body::Bytes(bytes) => write!(f,"Bytes({})",bytes.len()),
+            Body::Garbage(limit) => write!(f,"Garbage({:?})",limit),
+            Body::Stream(_,len) => write!(f,"Stream({})",len),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Response {
-    pub data : Vec<u8>,
-    pub chunk_count : i32
+    pub status : StatusCode,
+    pub headers : Vec<(String,String)>,
+    pub body : Body,
+    // HEAD request, headers describe the body but it is not sent
+    pub head_only : bool
+}
+
+pub struct ResponseBuilder {
+    response : Response
+}
+
+impl ResponseBuilder {
+    pub fn header(mut self,name : &str,value : &str) -> Self {
+        self.response.append_header(name,value);
+        self
+    }
+
+    pub fn no_cache(mut self) -> Self {
+        for (name,value) in NO_CACHE {
+            self.response.append_header(name,value);
+        }
+        self
+    }
+
+    pub fn cookie(self,cookie_data : &str) -> Self {
+        self.header("Set-Cookie",cookie_data)
+    }
+
+    pub fn bytes(mut self,bytes : Vec<u8>) -> Response {
+        self.response.body = Body::Bytes(bytes);
+        self.response
+    }
+
+    pub fn text(self,content : &str) -> Response {
+        self.bytes(content.as_bytes().to_vec())
+    }
+
+    pub fn garbage(mut self,limit : DownloadLimit) -> Response {
+        self.response.body = Body::Garbage(limit);
+        self.response
+    }
+
+    pub fn body(mut self,body : Body) -> Response {
+        self.response.body = body;
+        self.response
+    }
+
+    pub fn empty(self) -> Response {
+        self.response
+    }
 }
 
 impl Response {
 
-    pub fn res_404 () -> Self {
-        let body = b"404 not found";
-        let response_header = format!(
-            "HTTP/1.1 404 Not Found\r\n\
-            Content-Length: {}\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\r\n",
-            body.len()
-        );
-        let mut data = response_header.as_bytes().to_vec();
-        data.extend(body);
-        Response {data,chunk_count:0}
+    pub fn builder(status : StatusCode) -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response {
+                status,
+                headers: Vec::new(),
+                body: Body::Empty,
+                head_only: false,
+            }
+        }
     }
 
-    pub fn res_400 () -> Self {
-        let body = b"400 bad request";
-        let response_header = format!(
-            "HTTP/1.1 400 Bad Request\r\n\
-            Content-Length: {}\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\r\n",
-            body.len()
-        );
-        let mut data = response_header.as_bytes().to_vec();
-        data.extend(body);
-        Response {data,chunk_count:0}
+    pub fn header(&self,name : &str) -> Option<&str> {
+        self.headers.iter().find(|(key,_)| key.eq_ignore_ascii_case(name)).map(|(_,value)| value.as_str())
     }
 
-    pub fn res_200_img (img : &[u8]) -> Self {
-        let response_header = format!(
-            "HTTP/1.1 200 OK\r\n\
-            Content-Length: {}\r\n\
-            Content-Type: image/jpeg\r\n\
-            Cache-Control: no-store, no-cache, must-revalidate, max-age=0, s-maxage=0\r\n\
-            Cache-Control: post-check=0, pre-check=0\r\n\
-            Pragma: no-cache\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
-            Access-Control-Allow-Headers: Content-Encoding, Content-Type\r\n\r\n",
-            img.len()
-        );
-        let mut data = response_header.as_bytes().to_vec();
-        data.extend(img);
-        Response {data,chunk_count:0}
-    }
-
-    pub fn res_200_garbage (chunk_count : i32) -> Self {
-        let response_header = "HTTP/1.1 200 OK\r\n\
-            Content-Description: File Transfer\r\n\
-            Content-Type: application/octet-stream\r\n\
-            Content-Disposition: attachment; filename=random.dat\r\n\
-            Content-Transfer-Encoding: binary\r\n\
-            Connection: keep-alive\r\n\
-            Transfer-Encoding: chunked\r\n\
-            Cache-Control: no-store, no-cache, must-revalidate, max-age=0, s-maxage=0\r\n\
-            Cache-Control: post-check=0, pre-check=0\r\n\
-            Pragma: no-cache\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\r\n".to_string();
-        Response {
-            data : response_header.as_bytes().to_vec(),
-            chunk_count
+    pub fn append_header(&mut self,name : &str,value : &str) {
+        self.headers.push((name.to_string(),value.to_string()));
+    }
+
+    pub fn set_header(&mut self,name : &str,value : &str) {
+        self.remove_header(name);
+        self.append_header(name,value);
+    }
+
+    pub fn remove_header(&mut self,name : &str) {
+        self.headers.retain(|(key,_)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /*HEAD responses, same headers as GET without the body*/
+    pub fn without_body(mut self) -> Self {
+        self.head_only = true;
+        self
+    }
+
+    fn has_content_length(&self) -> bool {
+        !(self.status.is_informational() || self.status == StatusCode::NO_CONTENT || self.status == StatusCode::NOT_MODIFIED)
+    }
+
+    // headers including the body framing ones, shared by HTTP/1.1 and HTTP/2
+    pub fn all_headers(&self) -> Vec<(String,String)> {
+        let mut headers = self.headers.clone();
+        if self.has_content_length() && self.header("Content-Length").is_none() {
+            if let Some(len) = self.body.len() {
+                headers.push(("Content-Length".to_string(),len.to_string()));
+            }
         }
+        headers
     }
 
-    pub fn res_200_fs(file_name : &str) -> Self {
-        let file_name = if file_name == "/" { "/index.html" } else { file_name };
-        if let Some(file_content) = get_index_file_content(file_name) {
-            let content_type = match file_name {
-                i if i.ends_with(".js") => {
-                    "text/javascript"
-                }
-                i if i.ends_with(".html") => {
-                    "text/html"
-                }
-                i if i.ends_with(".ico") => {
-                    "image/vnd.microsoft.icon"
-                }
-                i if i.ends_with(".css") => {
-                    "text/css"
-                }
-                _ => {
-                    ""
-                }
-            };
-            let data = match content_type {
-                i if i == "text/javascript" || i == "text/html" || i == "text/css" => {
-                    let content = String::from_utf8(file_content).unwrap();
-                    format!(
-                        "HTTP/1.1 200 OK\r\n\
-                            Content-Type: {}\r\n\
-                            Content-Length: {}\r\n\
-                            Connection: keep-alive\r\n\
-                            Access-Control-Allow-Origin: *\r\n\
-                            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\r\n{}",
-                        content_type,
-                        content.len(),
-                        content
-                    ).as_bytes().to_vec()
+    pub fn serialize_head(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n",self.status.as_str(),self.status.canonical_reason().unwrap_or(""));
+        for (name,value) in self.all_headers() {
+            head.push_str(&format!("{}: {}\r\n",name,value));
+        }
+        if matches!(self.body,Body::Garbage(_)) {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    pub async fn write_http1<W>(self,remote_addr : &str,buf_writer : &mut BufWriter<W>) -> std::io::Result<()>
+    where
+        W: AsyncWriteExt + Unpin
+    {
+        buf_writer.write_all(&self.serialize_head()).await?;
+        if !self.head_only {
+            match self.body {
+                Body::Empty => {}
+                Body::Bytes(bytes) => {
+                    buf_writer.write_all(&bytes).await?;
                 }
-                "image/vnd.microsoft.icon" => {
-                    let response_header = format!(
-                        "HTTP/1.1 200 OK\r\n\
-                            Content-Type: {}\r\n\
-                            Content-Length: {}\r\n\
-                            Connection: keep-alive\r\n\
-                            Access-Control-Allow-Origin: *\r\n\
-                            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\r\n",
-                        content_type,
-                        file_content.len()
-                    );
-                    let mut data = response_header.as_bytes().to_vec();
-                    data.extend(file_content);
-                    data
+                Body::Garbage(limit) => {
+                    let mut garbage = GarbageSource::new(limit);
+                    let download_start = Instant::now();
+                    let mut download_bytes = 0;
+                    while let Some(chunk) = garbage.next_chunk() {
+                        let written = async {
+                            buf_writer.write_all(format!("{:X}\r\n",chunk.len()).as_bytes()).await?;
+                            buf_writer.write_all(&chunk).await?;
+                            buf_writer.write_all(b"\r\n").await
+                        }.await;
+                        if let Err(e) = written {
+                            measure::record_download(remote_addr,download_bytes,download_start,Instant::now());
+                            return Err(e)
+                        }
+                        download_bytes += chunk.len() as u64;
+                    }
+                    measure::record_download(remote_addr,download_bytes,download_start,Instant::now());
+                    buf_writer.write_all(b"0\r\n\r\n").await?;
                 }
-                _ => {
-                    return Self::res_404()
+                Body::Stream(mut reader,_) => {
+                    tokio::io::copy(&mut reader,buf_writer).await?;
                 }
-            };
-            Response {
-                data,
-                chunk_count : 0
             }
-        } else {
-            Self::res_404()
         }
+        buf_writer.flush().await
+    }
+
+    pub fn res_404 () -> Self {
+        Self::builder(StatusCode::NOT_FOUND).text("404 not found")
+    }
+
+    pub fn res_405 (allow : &str) -> Self {
+        Self::builder(StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow",allow)
+            .text("405 method not allowed")
+    }
+
+    /*preflight & OPTIONS responses*/
+    pub fn res_204_options (allow : &str) -> Self {
+        let cors = &SERVER_CONFIG.get().unwrap().cors;
+        Self::builder(StatusCode::NO_CONTENT)
+            .header("Allow",allow)
+            .header("Access-Control-Allow-Methods",allow)
+            .header("Access-Control-Allow-Headers",&cors.allow_headers.join(", "))
+            .header("Access-Control-Max-Age",&cors.max_age.to_string())
+            .empty()
+    }
+
+    pub fn res_403 () -> Self {
+        Self::builder(StatusCode::FORBIDDEN).text("403 forbidden")
+    }
+
+    pub fn res_301 (location : &str) -> Self {
+        Self::builder(StatusCode::MOVED_PERMANENTLY)
+            .header("Location",location)
+            .empty()
+    }
+
+    pub fn res_429 (retry_after : u64) -> Self {
+        Self::builder(StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After",&retry_after.to_string())
+            .text("429 too many requests")
+    }
+
+    pub fn res_308 (location : &str) -> Self {
+        Self::builder(StatusCode::PERMANENT_REDIRECT)
+            .header("Location",location)
+            .empty()
+    }
+
+    /*request over a request_limits size, the connection is closed after it*/
+    pub fn res_413 () -> Self {
+        Self::builder(StatusCode::PAYLOAD_TOO_LARGE)
+            .header("Connection","close")
+            .text("413 payload too large")
+    }
+
+    pub fn res_414 () -> Self {
+        Self::builder(StatusCode::URI_TOO_LONG)
+            .header("Connection","close")
+            .text("414 uri too long")
+    }
+
+    pub fn res_431 () -> Self {
+        Self::builder(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+            .header("Connection","close")
+            .text("431 request header fields too large")
+    }
+
+    pub fn res_400 () -> Self {
+        Self::builder(StatusCode::BAD_REQUEST).text("400 bad request")
+    }
+
+    pub fn res_200_img (img : &[u8]) -> Self {
+        Self::builder(StatusCode::OK)
+            .header("Content-Type","image/jpeg")
+            .no_cache()
+            .bytes(img.to_vec())
+    }
+
+    pub fn res_200_garbage (limit : DownloadLimit) -> Self {
+        Self::builder(StatusCode::OK)
+            .header("Content-Description","File Transfer")
+            .header("Content-Type","application/octet-stream")
+            .header("Content-Disposition","attachment; filename=random.dat")
+            .header("Content-Transfer-Encoding","binary")
+            .header("Connection","keep-alive")
+            .no_cache()
+            // proxies must not compress the measurement payload
+            .header("Cache-Control","no-transform")
+            .garbage(limit)
     }
 
     pub fn res_200_json(content : &str)  -> Self {
-        let response_header = format!(
-            "HTTP/1.1 200 OK\r\n\
-            Content-Length: {}\r\n\
-            Content-Type: application/json; charset=utf-8\r\n\
-            Cache-Control: no-store, no-cache, must-revalidate, max-age=0, s-maxage=0\r\n\
-            Cache-Control: post-check=0, pre-check=0\r\n\
-            Pragma: no-cache\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
-            Access-Control-Allow-Headers: Content-Encoding, Content-Type\r\n\r\n",
-            content.len()
-        );
-        let mut data = response_header.as_bytes().to_vec();
-        data.extend(content.as_bytes());
-        Response {data,chunk_count:0}
+        Self::builder(StatusCode::OK)
+            .header("Content-Type","application/json; charset=utf-8")
+            .no_cache()
+            .text(content)
     }
 
     pub fn res_200(content : &str) -> Self {
-        let response_header = format!(
-            "HTTP/1.1 200 OK\r\n\
-            Content-Length: {}\r\n\
-            Cache-Control: no-store, no-cache, must-revalidate, max-age=0, s-maxage=0\r\n\
-            Cache-Control: post-check=0, pre-check=0\r\n\
-            Pragma: no-cache\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
-            Access-Control-Allow-Headers: Content-Encoding, Content-Type\r\n\r\n{}",
-            content.len(),
-            content
-        );
-        Response {data : response_header.as_bytes().to_vec(),chunk_count : 0}
+        Self::builder(StatusCode::OK)
+            .no_cache()
+            .text(content)
     }
 
     pub fn res_500() -> Self {
-        let body = b"Internal Server Error";
-        let response_header = format!(
-            "HTTP/1.1 500 Internal Server Error\r\n\
-            Content-Length: {}\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\r\n",
-            body.len()
-        );
-        let mut data = response_header.as_bytes().to_vec();
-        data.extend(body);
-        Response {data,chunk_count:0}
+        Self::builder(StatusCode::INTERNAL_SERVER_ERROR).text("Internal Server Error")
     }
 
     /*stats responses*/
     pub fn res_temporary_redirect_cookie(cookie_data : &str,location : &str) -> Self {
-        let response_header = format!(
-            "HTTP/1.1 307 Temporary Redirect\r\n\
-            Content-Type: text/html; charset=utf-8\r\n\
-            Set-Cookie: {}\r\n\
-            Location: {}\r\n\
-            Content-Length: 0\r\n\
-            Cache-Control: no-store, no-cache, must-revalidate, max-age=0, s-maxage=0\r\n\
-            Cache-Control: post-check=0, pre-check=0\r\n\
-            Pragma: no-cache\r\n\
-            Access-Control-Allow-Credentials: true\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Headers: Content-Encoding, Content-Type, Authorization\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS, HEAD\r\n\r\n",
-            cookie_data,
-            location
-        );
-        let data = response_header.as_bytes().to_vec();
-        Response {data,chunk_count:0}
+        Self::builder(StatusCode::TEMPORARY_REDIRECT)
+            .header("Content-Type","text/html; charset=utf-8")
+            .cookie(cookie_data)
+            .header("Location",location)
+            .no_cache()
+            .empty()
     }
 
     pub fn res_200_html(content : &str) -> Self {
-        let response_header = format!(
-            "HTTP/1.1 200 OK\r\n\
-            Content-Length: {}\r\n\
-            Content-Type: text/html; charset=utf-8\r\n\
-            Cache-Control: no-store, no-cache, must-revalidate, max-age=0, s-maxage=0\r\n\
-            Cache-Control: post-check=0, pre-check=0\r\n\
-            Pragma: no-cache\r\n\
-            Access-Control-Allow-Credentials: true\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Headers: Content-Encoding, Content-Type, Authorization\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS, HEAD\r\n\r\n",
-            content.len()
-        );
-        let mut data = response_header.as_bytes().to_vec();
-        data.extend(content.as_bytes());
-        Response {data,chunk_count:0}
+        Self::builder(StatusCode::OK)
+            .header("Content-Type","text/html; charset=utf-8")
+            .no_cache()
+            .text(content)
     }
 
     pub fn res_403_html(content : &str) -> Self {
-        let response_header = format!(
-            "HTTP/1.1 403 Forbidden\r\n\
-            Content-Length: {}\r\n\
-            Content-Type: text/html; charset=utf-8\r\n\
-            Cache-Control: no-store, no-cache, must-revalidate, max-age=0, s-maxage=0\r\n\
-            Cache-Control: post-check=0, pre-check=0\r\n\
-            Pragma: no-cache\r\n\
-            Access-Control-Allow-Credentials: true\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Headers: Content-Encoding, Content-Type, Authorization\r\n\
-            Access-Control-Allow-Methods: GET, POST, OPTIONS, HEAD\r\n\r\n",
-            content.len()
-        );
-        let mut data = response_header.as_bytes().to_vec();
-        data.extend(content.as_bytes());
-        Response {data,chunk_count:0}
+        Self::builder(StatusCode::FORBIDDEN)
+            .header("Content-Type","text/html; charset=utf-8")
+            .no_cache()
+            .text(content)
     }
 
 }
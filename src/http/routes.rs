@@ -1,14 +1,47 @@
-use std::collections::HashMap;
 use std::sync::Arc;
 
+use serde_json::json;
 use tokio::sync::Mutex;
 
 use crate::config::SERVER_CONFIG;
 use crate::database::Database;
+use crate::http::Method;
+use crate::http::params::Params;
 use crate::http::request::Request;
 use crate::http::response::Response;
+use crate::results::measure;
 use crate::results::telemetry::{draw_result, record_result};
 
+const METHODS_READ : &[Method] = &[Method::Get, Method::Head, Method::Options];
+const METHODS_READ_WRITE : &[Method] = &[Method::Get, Method::Head, Method::Post, Method::Options];
+const METHODS_WRITE : &[Method] = &[Method::Post, Method::Options];
+const METHODS_UPGRADE : &[Method] = &[Method::Get, Method::Options];
+
+// methods accepted by each route, `None` is a static file
+pub fn allowed_methods(route : Option<&str>) -> &'static [Method] {
+    match route {
+        Some("empty") | Some("stats") => METHODS_READ_WRITE,
+        Some("results/telemetry") => METHODS_WRITE,
+        Some("ws") => METHODS_UPGRADE,
+        _ => METHODS_READ
+    }
+}
+
+pub fn empty_route(request : &Request) -> Response {
+    let body_stats = request.body_stats;
+    if body_stats.bytes == 0 {
+        return Response::res_200("")
+    }
+    measure::record_upload(&request.remote_addr,body_stats.bytes,body_stats.start,body_stats.start + body_stats.duration);
+    //upload test, report what the server actually received
+    let upload_stats = json!({
+        "bytes": body_stats.bytes,
+        "duration_ms": body_stats.duration.as_secs_f64() * 1000.0,
+        "mbps": body_stats.mbps()
+    });
+    Response::res_200_json(&upload_stats.to_string())
+}
+
 pub async fn telemetry_record_route(database : &mut Arc<Mutex<dyn Database + Send>>,request : &Request) -> Response {
     let server_config = SERVER_CONFIG.get().unwrap();
     match server_config.database_type.as_str() {
@@ -30,7 +63,7 @@ pub async fn telemetry_record_route(database : &mut Arc<Mutex<dyn Database + Sen
     }
 }
 
-pub async fn show_result_route (database : &mut Arc<Mutex<dyn Database + Send>>, params: &HashMap<String, String>) -> Response {
+pub async fn show_result_route (database : &mut Arc<Mutex<dyn Database + Send>>, params: &Params) -> Response {
     let result_id = params.get("id");
     match result_id {
         Some(result_id) => {
@@ -1,4 +1,4 @@
-use crate::config::ServerConfig;
+use crate::config::{ListenerConfig, ServerConfig};
 use core::fmt;
 use socket2::{Domain, Type};
 use std::fmt::Formatter;
@@ -8,13 +8,19 @@ use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use tokio::net::{TcpListener, TcpStream};
 use futures::future::select_all;
+use std::sync::Arc;
 use log::info;
 use tokio::{select, signal};
+use tokio::sync::Notify;
+#[cfg(unix)]
+use crate::http::upgrade;
 
 pub struct TcpSocket {
     tcp_listeners: Vec<TcpListener>,
     addrs: Vec<TcpAddr>,
     pub(crate) shutdown_tx : tokio::sync::broadcast::Sender<()>,
+    // SIGUSR2, the listeners are handed to a new process
+    upgrade : Arc<Notify>,
     from_sys: bool,
 }
 
@@ -24,18 +30,31 @@ impl TcpSocket {
         let (tcp_listeners, tcp_addr, from_sys) = if !fd_listeners.0.is_empty() {
             (fd_listeners.0, fd_listeners.1, true)
         } else {
-            let tcp_addr = TcpAddr::from_config(config)?;
-            (vec![Self::bind(&tcp_addr)?], vec![tcp_addr], false)
+            let tcp_addrs = config.listeners.iter()
+                .map(|listener| TcpAddr::from_listener(listener,&config.listeners))
+                .collect::<io::Result<Vec<TcpAddr>>>()?;
+            let tcp_listeners = tcp_addrs.iter().map(Self::bind).collect::<io::Result<Vec<TcpListener>>>()?;
+            (tcp_listeners, tcp_addrs, false)
         };
         let (shutdown_tx, _) = tokio::sync::broadcast::channel::<()>(1);
         Ok(TcpSocket {
             tcp_listeners,
             addrs: tcp_addr,
             shutdown_tx,
+            upgrade: Arc::new(Notify::new()),
             from_sys,
         })
     }
 
+    pub fn listener_count(&self) -> usize {
+        self.tcp_listeners.len()
+    }
+
+    // new connections are refused from now on, accepted ones are untouched
+    pub fn close_listeners(&mut self) {
+        self.tcp_listeners.clear();
+    }
+
     fn find_fd_listeners() -> io::Result<(Vec<TcpListener>, Vec<TcpAddr>)> {
         let mut listen_fd = listenfd::ListenFd::from_env();
         let mut fd_listeners = Vec::new();
@@ -56,8 +75,8 @@ impl TcpSocket {
 
     fn bind(tcp_addr: &TcpAddr) -> io::Result<TcpListener> {
         let socket = socket2::Socket::new(tcp_addr.domain, Type::STREAM, None)?;
-        if !tcp_addr.is_only_v6 {
-            socket.set_only_v6(false)?;
+        if tcp_addr.domain == Domain::IPV6 {
+            socket.set_only_v6(tcp_addr.is_only_v6)?;
         }
         socket.set_reuse_address(true)?;
         socket.bind(&tcp_addr.sock_addr.into())?;
@@ -67,7 +86,8 @@ impl TcpSocket {
         Ok(tcp_listener)
     }
 
-    pub async fn accept(&self,shutdown_rx: &mut tokio::sync::broadcast::Receiver<()>) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+    // accepted stream with the index of the listener it came from
+    pub async fn accept(&self,shutdown_rx: &mut tokio::sync::broadcast::Receiver<()>) -> io::Result<Option<(TcpStream, SocketAddr, usize)>> {
         if self.tcp_listeners.is_empty() {
             return Err(Error::new(
                 ErrorKind::NotConnected,
@@ -75,19 +95,37 @@ impl TcpSocket {
             ));
         }
     
-        let accept_futures = self.tcp_listeners.iter().map(|listener| {
-            Box::pin(listener.accept())
-        });
+        loop {
+            let accept_futures = self.tcp_listeners.iter().map(|listener| {
+                Box::pin(listener.accept())
+            });
 
-        select! {
-            res = select_all(accept_futures) => {
-                let (result, _index, _remaining) = res;
-                result.map(Some)
+            select! {
+                res = select_all(accept_futures) => {
+                    let (result, index, _remaining) = res;
+                    return result.map(|(stream, addr)| Some((stream, addr, index)))
+                }
+                _ = shutdown_rx.recv() => return Ok(None),
+                _ = self.upgrade.notified() => self.start_upgrade(),
             }
-            _ = shutdown_rx.recv() => Ok(None),
         }
     }
 
+    #[cfg(unix)]
+    fn start_upgrade(&self) {
+        use std::os::fd::AsFd;
+        let listener_fds = self.tcp_listeners.iter()
+            .map(|listener| listener.as_fd().try_clone_to_owned())
+            .collect::<io::Result<Vec<_>>>();
+        match listener_fds {
+            Ok(listener_fds) => upgrade::start_upgrade(listener_fds,self.shutdown_tx.clone()),
+            Err(e) => log::error!("Upgrade failed, cannot duplicate the listeners : {e}")
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn start_upgrade(&self) {}
+
     pub fn spawn_signal_handler(&self) {
         let shutdown_tx = self.shutdown_tx.clone();
         tokio::spawn(async move {
@@ -111,26 +149,35 @@ impl TcpSocket {
             info!("SIGTERM / Ctrl+C received, notifying tasks ...");
             let _ = shutdown_tx.send(());
         });
+
+        #[cfg(unix)]
+        {
+            let upgrade = self.upgrade.clone();
+            tokio::spawn(async move {
+                if let Ok(mut usr2) = signal::unix::signal(signal::unix::SignalKind::user_defined2()) {
+                    while usr2.recv().await.is_some() {
+                        info!("SIGUSR2 received, starting the new binary ...");
+                        upgrade.notify_one();
+                    }
+                }
+            });
+        }
     }
 
 }
 
 impl fmt::Display for TcpSocket {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self.from_sys {
-            true => {
-                for (i, addr) in self.addrs.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, ", ")?;
-                    }
-                    write!(f, "{}", addr.sock_addr)?;
-                }
-                Ok(())
-            }
-            false => {
-                write!(f, "{}", self.addrs[0].sock_addr)
+        for (i, addr) in self.addrs.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
             }
+            write!(f, "{}", addr.sock_addr)?;
         }
+        if self.from_sys {
+            write!(f, " (socket activation)")?;
+        }
+        Ok(())
     }
 }
 
@@ -142,14 +189,18 @@ pub struct TcpAddr {
 }
 
 impl TcpAddr {
-    pub fn from_config(config: &ServerConfig) -> io::Result<Self> {
-        let bind_addr = config.bind_address.as_str();
+    pub fn from_listener(listener: &ListenerConfig, listeners: &[ListenerConfig]) -> io::Result<Self> {
+        let bind_addr = listener.address.as_str();
         let parsed_addr = bind_addr.parse_addr()?;
-        let addr = SocketAddr::new(parsed_addr.0, config.listen_port);
+        let addr = SocketAddr::new(parsed_addr.0, listener.port);
+        // `::` is dual stack, unless an ipv4 listener takes the same port
+        let ipv4_on_port = listeners.iter().any(|other| {
+            other.port == listener.port && other.address.parse::<IpAddr>().is_ok_and(|ip| ip.is_ipv4())
+        });
         Ok(TcpAddr {
             sock_addr: addr,
             domain: parsed_addr.1,
-            is_only_v6: bind_addr != "::" && bind_addr != "::0",
+            is_only_v6: (bind_addr != "::" && bind_addr != "::0") || ipv4_on_port,
         })
     }
 
@@ -1,35 +1,291 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Error, ErrorKind};
-use std::sync::Arc;
-use log::info;
+use std::io::{BufReader, Error};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{error, info, warn};
 use rustls_pemfile::{certs, private_key};
 use tokio::net::TcpStream;
+use tokio::{select, signal};
+use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, ServerConnection, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
 use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+use crate::config::SERVER_CONFIG;
+use crate::config::time::convert_time_utc;
 use tokio_rustls::{TlsAcceptor, TlsConnector};
 use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls::pki_types::ServerName;
 
+// client certificates signed by the client_auth CA bundle, anonymous clients are let in unless required
+fn client_verifier() -> std::io::Result<Option<Arc<dyn ClientCertVerifier>>> {
+    let Some(client_auth) = SERVER_CONFIG.get().map(|config| &config.client_auth).filter(|client_auth| !client_auth.ca_file.is_empty()) else {
+        return Ok(None)
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&client_auth.ca_file)? {
+        roots.add(cert).map_err(|e| Error::other(format!("Invalid client CA certificate in {} : {e}",client_auth.ca_file)))?;
+    }
+    let builder = WebPkiClientVerifier::builder(Arc::new(roots));
+    let builder = if client_auth.required { builder } else { builder.allow_unauthenticated() };
+    let verifier = builder.build().map_err(|e| Error::other(format!("Invalid client CA file {} : {e}",client_auth.ca_file)))?;
+    info!("TLS client authentication with CA file {}",client_auth.ca_file);
+    Ok(Some(verifier))
+}
+
+// subject of the verified client certificate, `CN=..., O=...`
+pub fn client_subject(connection : &ServerConnection) -> Option<String> {
+    let cert = connection.peer_certificates()?.first()?;
+    let (_,cert) = parse_x509_certificate(cert.as_ref()).ok()?;
+    Some(cert.subject().to_string())
+}
+
+// routes restricted to verified tls clients by client_auth
+pub fn requires_client_cert(route : Option<&str>) -> bool {
+    let Some(client_auth) = SERVER_CONFIG.get().map(|config| &config.client_auth) else {
+        return false
+    };
+    match route {
+        Some(route) => !client_auth.ca_file.is_empty() && client_auth.routes.iter().any(|protected| protected == route),
+        None => false
+    }
+}
+
+// certificates expiring within this window are logged as warnings
+const EXPIRY_WARNING : Duration = Duration::from_secs(14 * 24 * 3600);
+const RELOAD_POLL_INTERVAL : Duration = Duration::from_secs(30);
+const EXPIRY_CHECK_INTERVAL : Duration = Duration::from_secs(24 * 3600);
+
+static RESOLVERS: OnceLock<Mutex<Vec<Arc<CertResolver>>>> = OnceLock::new();
+
 /** TLS Configuration */
 pub fn setup_tls_acceptor(cert_path : &str,key_path : &str) -> std::io::Result<TlsAcceptor> {
-    let certs = load_certs(cert_path)?;
-    let key = load_key(key_path)?;
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let mut sources = vec![(cert_path.to_string(),key_path.to_string())];
+    if let Some(config) = SERVER_CONFIG.get() {
+        sources.extend(config.tls_certificates.iter().map(|cert| (cert.cert_file.clone(),cert.key_file.clone())));
+    }
+    let resolver = Arc::new(CertResolver::new(sources)?);
+    let builder = ServerConfig::builder();
+    let builder = match client_verifier()? {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth()
+    };
+    let mut config = builder.with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
     let acceptor = TlsAcceptor::from(Arc::new(config));
+    RESOLVERS.get_or_init(|| Mutex::new(Vec::new())).lock().unwrap().push(resolver);
     info!("Server TLS successfully configured");
     Ok(acceptor)
 }
 
-pub async fn setup_tls_connector(domain : String,tcp_stream: TcpStream) -> TlsStream<TcpStream> {
+/*loaded certificates, the first one is used when SNI matches none*/
+#[derive(Debug)]
+struct CertStore {
+    default : Arc<CertifiedKey>,
+    by_name : HashMap<String,Arc<CertifiedKey>>,
+    // expiry of every certificate file, seconds since epoch
+    not_after : Vec<(String,i64)>
+}
+
+/*SNI certificate selection, swapped atomically on reload so open connections keep their certificate*/
+#[derive(Debug)]
+pub struct CertResolver {
+    sources : Vec<(String,String)>,
+    store : RwLock<Arc<CertStore>>,
+    modified : Mutex<Vec<Option<SystemTime>>>
+}
+
+impl CertResolver {
+    fn new(sources : Vec<(String,String)>) -> std::io::Result<Self> {
+        let store = load_store(&sources)?;
+        log_expiry(&store);
+        Ok(CertResolver {
+            modified: Mutex::new(files_modified(&sources)),
+            sources,
+            store: RwLock::new(Arc::new(store)),
+        })
+    }
+
+    // a failed reload keeps the current certificates
+    fn reload(&self) {
+        *self.modified.lock().unwrap() = files_modified(&self.sources);
+        match load_store(&self.sources) {
+            Ok(store) => {
+                log_expiry(&store);
+                *self.store.write().unwrap() = Arc::new(store);
+                info!("TLS certificates reloaded");
+            }
+            Err(e) => {
+                error!("TLS certificates reload failed, keeping current ones : {e}");
+            }
+        }
+    }
+
+    fn is_changed(&self) -> bool {
+        *self.modified.lock().unwrap() != files_modified(&self.sources)
+    }
+}
+
+impl CertStore {
+    fn find(&self,server_name : &str) -> Option<&Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+        if let Some(key) = self.by_name.get(&server_name) {
+            return Some(key)
+        }
+        // `*.example.com` covers one label
+        let (_,parent) = server_name.split_once('.')?;
+        self.by_name.get(&format!("*.{parent}"))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self,client_hello : ClientHello) -> Option<Arc<CertifiedKey>> {
+        let store = self.store.read().unwrap().clone();
+        if let Some(key) = client_hello.server_name().and_then(|server_name| store.find(server_name)) {
+            return Some(key.clone())
+        }
+        Some(store.default.clone())
+    }
+}
+
+// a name one of the loaded certificates is valid for, issued ones cover the acme domains
+pub fn has_certificate_for(server_name : &str) -> bool {
+    RESOLVERS.get().is_some_and(|resolvers| {
+        resolvers.lock().unwrap().iter().any(|resolver| resolver.store.read().unwrap().find(server_name).is_some())
+    })
+}
+
+fn files_modified(sources : &[(String,String)]) -> Vec<Option<SystemTime>> {
+    sources.iter()
+        .flat_map(|(cert,key)| [cert,key])
+        .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+fn load_store(sources : &[(String,String)]) -> std::io::Result<CertStore> {
+    let mut default = None;
+    let mut by_name = HashMap::new();
+    let mut not_after = Vec::new();
+    for (cert_path,key_path) in sources {
+        let certs = load_certs(cert_path)?;
+        let key = load_key(key_path)?;
+        let Some(leaf) = certs.first() else {
+            return Err(Error::other(format!("No certificate found in {cert_path}")))
+        };
+        let (names,expiry) = certificate_info(leaf)
+            .ok_or_else(|| Error::other(format!("Failed to parse tls cert file {cert_path}")))?;
+        let signing_key = any_supported_type(&key)
+            .map_err(|e| Error::other(format!("Unsupported tls key file {key_path} : {e}")))?;
+        let certified_key = CertifiedKey::new(certs,signing_key);
+        // a key rewritten before its certificate
+        certified_key.keys_match()
+            .map_err(|e| Error::other(format!("Tls key file {key_path} does not match {cert_path} : {e}")))?;
+        let certified_key = Arc::new(certified_key);
+        for name in names {
+            by_name.entry(name).or_insert_with(|| certified_key.clone());
+        }
+        not_after.push((cert_path.clone(),expiry));
+        default.get_or_insert(certified_key);
+    }
+    let default = default.ok_or_else(|| Error::other("No tls certificate configured"))?;
+    Ok(CertStore { default, by_name, not_after })
+}
+
+// DNS names from subjectAltName (common name when there is none) and the expiry time
+pub(crate) fn certificate_info(cert : &CertificateDer) -> Option<(Vec<String>,i64)> {
+    let (_,cert) = parse_x509_certificate(cert.as_ref()).ok()?;
+    let mut names = Vec::new();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_ascii_lowercase());
+            }
+        }
+    }
+    if names.is_empty() {
+        if let Some(common_name) = cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()) {
+            names.push(common_name.to_ascii_lowercase());
+        }
+    }
+    Some((names,cert.validity().not_after.timestamp()))
+}
+
+fn log_expiry(store : &CertStore) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0);
+    for (cert_path,not_after) in &store.not_after {
+        let expiry = convert_time_utc(not_after * 1000);
+        if *not_after <= now {
+            error!("TLS certificate {cert_path} expired on {expiry} UTC");
+        } else if *not_after - now <= EXPIRY_WARNING.as_secs() as i64 {
+            warn!("TLS certificate {cert_path} expires soon, on {expiry} UTC");
+        } else {
+            info!("TLS certificate {cert_path} valid until {expiry} UTC");
+        }
+    }
+}
+
+// certificate files rewritten by the server itself
+pub fn reload_certificates() {
+    if let Some(resolvers) = RESOLVERS.get() {
+        resolvers.lock().unwrap().iter().filter(|resolver| resolver.is_changed()).for_each(|resolver| resolver.reload());
+    }
+}
+
+/*reload on SIGHUP or when a certificate file changes, expiry is logged daily*/
+pub fn spawn_certificate_reloader() {
+    let Some(resolvers) = RESOLVERS.get() else {
+        return
+    };
+    let resolvers = resolvers.lock().unwrap().clone();
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup()).ok();
+        let mut poll = tokio::time::interval(RELOAD_POLL_INTERVAL);
+        let mut expiry_check = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        poll.tick().await;
+        expiry_check.tick().await;
+        loop {
+            #[cfg(unix)]
+            let hangup_signal = async {
+                match hangup.as_mut() {
+                    Some(hangup) => { hangup.recv().await; }
+                    None => std::future::pending::<()>().await
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup_signal = std::future::pending::<()>();
+            select! {
+                _ = hangup_signal => {
+                    info!("SIGHUP received, reloading TLS certificates ...");
+                    resolvers.iter().for_each(|resolver| resolver.reload());
+                }
+                _ = poll.tick() => {
+                    resolvers.iter().filter(|resolver| resolver.is_changed()).for_each(|resolver| resolver.reload());
+                }
+                _ = expiry_check.tick() => {
+                    resolvers.iter().for_each(|resolver| log_expiry(&resolver.store.read().unwrap()));
+                }
+            }
+        }
+    });
+}
+
+pub async fn setup_tls_connector(domain : String,tcp_stream: TcpStream,ca_file : Option<&str>) -> std::io::Result<TlsStream<TcpStream>> {
     let mut root_cert_store = RootCertStore::empty();
     root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca_file) = ca_file {
+        for cert in load_certs(ca_file)? {
+            root_cert_store.add(cert).map_err(|e| Error::other(format!("Invalid CA certificate in {ca_file} : {e}")))?;
+        }
+    }
     let config = ClientConfig::builder().with_root_certificates(root_cert_store).with_no_client_auth();
-    let dns_name = ServerName::try_from(domain).unwrap();
+    let dns_name = ServerName::try_from(domain).map_err(Error::other)?;
     let connector = TlsConnector::from(Arc::new(config));
-    connector.connect(dns_name, tcp_stream).await.unwrap()
+    connector.connect(dns_name, tcp_stream).await
 }
 
 fn open_file_buf(path : &str,err_msg : &str) -> std::io::Result<File> {
@@ -45,6 +301,7 @@ fn load_certs(path: &str) -> std::io::Result<Vec<CertificateDer<'static>>> {
 }
 
 fn load_key(path: &str) -> std::io::Result<PrivateKeyDer<'static>> {
-    private_key(&mut BufReader::new(open_file_buf(path,"Failed to load tls key file")?)).unwrap()
-        .ok_or(Error::other("Failed to load tls key file".to_string()))
+    private_key(&mut BufReader::new(open_file_buf(path,"Failed to load tls key file")?))
+        .map_err(|e| Error::other(format!("Failed to load tls key file {path} : {e}")))?
+        .ok_or(Error::other(format!("Failed to load tls key file {path} : no private key found")))
 }
\ No newline at end of file
@@ -0,0 +1,74 @@
+use std::io::Error;
+use std::os::fd::{OwnedFd, RawFd};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use command_fds::{CommandFdExt, FdMapping};
+use log::{error, info, warn};
+use tokio::sync::broadcast;
+
+/*
+Zero downtime upgrade on SIGUSR2 :
+the binary is started again with the listening sockets passed as LISTEN_FDS (picked up by `find_fd_listeners`),
+both processes accept while the new one starts, then this one drains its connections and exits.
+*/
+
+// first fd of the LISTEN_FDS protocol
+const LISTEN_FDS_START : RawFd = 3;
+// the new process must still be running after it to take over
+const STARTUP_CHECK : Duration = Duration::from_secs(5);
+
+static UPGRADING : AtomicBool = AtomicBool::new(false);
+
+pub fn start_upgrade(listener_fds : Vec<OwnedFd>,shutdown_tx : broadcast::Sender<()>) {
+    if UPGRADING.swap(true,Ordering::SeqCst) {
+        warn!("Upgrade already in progress");
+        return;
+    }
+    let mut child = match spawn_process(listener_fds) {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Upgrade failed, cannot start the new process : {e}");
+            UPGRADING.store(false,Ordering::SeqCst);
+            return;
+        }
+    };
+    info!("New process {} started, handing over in {}s ...",child.id(),STARTUP_CHECK.as_secs());
+    tokio::spawn(async move {
+        tokio::time::sleep(STARTUP_CHECK).await;
+        match child.try_wait() {
+            Ok(None) => {
+                info!("Handing over to process {}",child.id());
+                let _ = shutdown_tx.send(());
+            }
+            Ok(Some(status)) => {
+                error!("Upgrade aborted, the new process exited ({status})");
+                UPGRADING.store(false,Ordering::SeqCst);
+            }
+            Err(e) => {
+                error!("Upgrade aborted, cannot check the new process : {e}");
+                UPGRADING.store(false,Ordering::SeqCst);
+            }
+        }
+    });
+}
+
+// same program & arguments, the binary on disk may have been replaced
+fn spawn_process(listener_fds : Vec<OwnedFd>) -> std::io::Result<Child> {
+    let mut args = std::env::args_os();
+    let program = args.next().ok_or_else(|| Error::other("program path unavailable"))?;
+    let fd_count = listener_fds.len();
+    let mappings = listener_fds.into_iter().enumerate().map(|(index,fd)| FdMapping {
+        parent_fd: fd,
+        child_fd: LISTEN_FDS_START + index as RawFd
+    }).collect();
+    let mut command = Command::new(program);
+    command.args(args)
+        .env("LISTEN_FDS",fd_count.to_string())
+        // no pid check, the pid of the new process is unknown before it runs
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS_FIRST_FD")
+        .fd_mappings(mappings)
+        .map_err(Error::other)?;
+    command.spawn()
+}
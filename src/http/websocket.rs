@@ -0,0 +1,395 @@
+use std::io::{Error, ErrorKind};
+use std::time::Instant;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use case_insensitive_hashmap::CaseInsensitiveHashMap as CIHashMap;
+use http::StatusCode;
+use log::trace;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::select;
+use crate::config::RequestLimitsConfig;
+use crate::config::time::get_current_millis;
+use crate::http::garbage::GarbageSource;
+use crate::http::{drain, get_download_limit, limiter};
+use crate::http::params::Params;
+use crate::http::request::{fill_body_buf, request_limits, with_timeout};
+use crate::results::{measure, metrics};
+
+/*
+WebSocket speedtest transport (RFC 6455)
+client text commands :
+  download <ckSize>  -> server sends ckSize MiB in binary frames of garbage_chunk_size bytes, then text `done`
+  upload             -> resets upload counters, binary frames sent by client are discarded
+  upload_end         -> server replies json {"bytes","duration_ms","mbps"} of received upload
+  ping <payload>     -> server replies `pong <payload> <server_millis>`
+download & upload are checked against the client limits, over them the server replies
+`error too many requests <retry_after_seconds>` and closes with 1013
+*/
+
+const WS_GUID : &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_TEXT_MESSAGE : usize = 4096;
+// close, ping & pong payloads (RFC 6455 5.5)
+const MAX_CONTROL_PAYLOAD : u64 = 125;
+
+const OP_CONTINUATION : u8 = 0x0;
+const OP_TEXT : u8 = 0x1;
+const OP_BINARY : u8 = 0x2;
+const OP_CLOSE : u8 = 0x8;
+const OP_PING : u8 = 0x9;
+const OP_PONG : u8 = 0xA;
+
+const CLOSE_GOING_AWAY : u16 = 1001;
+const CLOSE_PROTOCOL_ERROR : u16 = 1002;
+const CLOSE_TOO_BIG : u16 = 1009;
+const CLOSE_TRY_AGAIN_LATER : u16 = 1013;
+
+pub fn is_upgrade_request(headers : &CIHashMap<String>) -> bool {
+    let upgrade = headers.get("Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+    let connection = headers.get("Connection").is_some_and(|c| {
+        c.split(',').any(|part| part.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    upgrade && connection
+}
+
+fn handshake_response(headers : &CIHashMap<String>) -> Vec<u8> {
+    let key = headers.get("Sec-WebSocket-Key").map(|k| k.trim());
+    let version = headers.get("Sec-WebSocket-Version").map(|v| v.trim());
+    match (key,version) {
+        (Some(key),Some("13")) if !key.is_empty() => {
+            let mut hasher = Sha1::new();
+            hasher.update(key.as_bytes());
+            hasher.update(WS_GUID.as_bytes());
+            let accept = BASE64.encode(hasher.finalize());
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            ).into_bytes()
+        }
+        _ => {
+            "HTTP/1.1 426 Upgrade Required\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Content-Length: 0\r\n\
+            Connection: close\r\n\r\n".as_bytes().to_vec()
+        }
+    }
+}
+
+struct Frame {
+    fin : bool,
+    opcode : u8,
+    length : u64,
+    mask : [u8;4]
+}
+
+async fn read_frame_header<R>(buf_reader : &mut BufReader<R>) -> std::io::Result<Frame>
+where
+    R: AsyncReadExt + Unpin
+{
+    let mut head = [0u8;2];
+    buf_reader.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[1] & 0x80 == 0 {
+        return Err(Error::new(ErrorKind::InvalidData,"client frame is not masked"))
+    }
+    let length = match head[1] & 0x7F {
+        126 => buf_reader.read_u16().await? as u64,
+        127 => buf_reader.read_u64().await?,
+        len => len as u64
+    };
+    let mut mask = [0u8;4];
+    buf_reader.read_exact(&mut mask).await?;
+    Ok(Frame { fin, opcode, length, mask })
+}
+
+// frame header within body_timeout
+async fn read_frame_header_timeout<R>(buf_reader : &mut BufReader<R>,limits : &RequestLimitsConfig) -> std::io::Result<Frame>
+where
+    R: AsyncReadExt + Unpin
+{
+    match with_timeout(limits.body_timeout,read_frame_header(buf_reader)).await {
+        Some(frame) => frame,
+        None => Err(Error::new(ErrorKind::TimedOut,"frame read timeout"))
+    }
+}
+
+// control & text payloads only, the length is checked against their limits before
+async fn read_payload<R>(buf_reader : &mut BufReader<R>,frame : &Frame,limits : &RequestLimitsConfig) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncReadExt + Unpin
+{
+    if frame.length > MAX_TEXT_MESSAGE as u64 {
+        return Err(Error::new(ErrorKind::InvalidData,"frame payload too large"))
+    }
+    let mut payload = vec![0u8;frame.length as usize];
+    if with_timeout(limits.body_timeout,buf_reader.read_exact(&mut payload)).await.transpose()?.is_none() {
+        return Err(Error::new(ErrorKind::TimedOut,"frame read timeout"))
+    }
+    for (i,byte) in payload.iter_mut().enumerate() {
+        *byte ^= frame.mask[i % 4];
+    }
+    Ok(payload)
+}
+
+// upload payloads, body_timeout applies to every read
+async fn discard_payload<R>(buf_reader : &mut BufReader<R>,length : u64,limits : &RequestLimitsConfig) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin
+{
+    let mut remaining = length;
+    while remaining > 0 {
+        let available = fill_body_buf(buf_reader,limits).await?.len();
+        if available == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof,"frame ended before payload length"))
+        }
+        let consumed = available.min(remaining as usize);
+        buf_reader.consume(consumed);
+        remaining -= consumed as u64;
+    }
+    Ok(())
+}
+
+fn frame_header(opcode : u8,length : usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(10);
+    header.push(0x80 | opcode);
+    match length {
+        0..=125 => header.push(length as u8),
+        126..=65535 => {
+            header.push(126);
+            header.extend((length as u16).to_be_bytes());
+        }
+        _ => {
+            header.push(127);
+            header.extend((length as u64).to_be_bytes());
+        }
+    }
+    header
+}
+
+async fn write_frame<W>(buf_writer : &mut BufWriter<W>,opcode : u8,payload : &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin
+{
+    buf_writer.write_all(&frame_header(opcode,payload.len())).await?;
+    buf_writer.write_all(payload).await?;
+    buf_writer.flush().await
+}
+
+async fn write_close<W>(buf_writer : &mut BufWriter<W>,code : u16) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin
+{
+    write_frame(buf_writer,OP_CLOSE,&code.to_be_bytes()).await
+}
+
+#[derive(Default)]
+struct UploadCounter {
+    bytes : u64,
+    start : Option<Instant>,
+    end : Option<Instant>
+}
+
+impl UploadCounter {
+    fn report(&self) -> String {
+        let duration = match (self.start,self.end) {
+            (Some(start),Some(end)) => end.duration_since(start).as_secs_f64(),
+            _ => 0.0
+        };
+        let mbps = if duration > 0.0 { (self.bytes as f64 * 8.0) / duration / 1_000_000.0 } else { 0.0 };
+        serde_json::json!({
+            "bytes": self.bytes,
+            "duration_ms": duration * 1000.0,
+            "mbps": mbps
+        }).to_string()
+    }
+}
+
+pub async fn handle_websocket<R,W>(remote_addr : &str,headers : &CIHashMap<String>,buf_reader : &mut BufReader<R>,buf_writer : &mut BufWriter<W>)
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin
+{
+    let handshake = handshake_response(headers);
+    let accepted = handshake.starts_with(b"HTTP/1.1 101");
+    metrics::record_request("ws",if accepted { StatusCode::SWITCHING_PROTOCOLS } else { StatusCode::UPGRADE_REQUIRED });
+    if let Err(e) = buf_writer.write_all(&handshake).await {
+        trace!("Error websocket handshake : {e}");
+        return;
+    }
+    if let Err(e) = buf_writer.flush().await {
+        trace!("Error websocket handshake : {e}");
+        return;
+    }
+    if !accepted {
+        return;
+    }
+    if let Err(e) = websocket_loop(remote_addr,buf_reader,buf_writer).await {
+        trace!("Websocket closed : {e}")
+    }
+}
+
+async fn websocket_loop<R,W>(remote_addr : &str,buf_reader : &mut BufReader<R>,buf_writer : &mut BufWriter<W>) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin
+{
+    let mut upload = UploadCounter::default();
+    let mut message : Vec<u8> = Vec::new();
+    let mut message_opcode = OP_TEXT;
+    let limits = request_limits();
+    loop {
+        // between tests : closed after keep_alive_timeout or when the server goes away, a running upload is left to finish
+        if upload.start.is_none() && message.is_empty() {
+            let idle = async {
+                with_timeout(limits.keep_alive_timeout,buf_reader.fill_buf()).await.is_none()
+            };
+            let timed_out = select! {
+                timed_out = idle => timed_out,
+                _ = drain::drain_started() => {
+                    write_close(buf_writer,CLOSE_GOING_AWAY).await?;
+                    return Ok(())
+                }
+            };
+            if timed_out {
+                write_close(buf_writer,CLOSE_GOING_AWAY).await?;
+                return Err(Error::new(ErrorKind::TimedOut,"idle timeout"))
+            }
+        }
+        let frame = match read_frame_header_timeout(buf_reader,limits).await {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                write_close(buf_writer,CLOSE_PROTOCOL_ERROR).await?;
+                return Err(e)
+            }
+            Err(e) => {
+                record_upload(remote_addr,&upload);
+                return Err(e)
+            }
+        };
+        // control frames are short & never fragmented
+        if frame.opcode & 0x8 != 0 && (frame.length > MAX_CONTROL_PAYLOAD || !frame.fin) {
+            record_upload(remote_addr,&upload);
+            write_close(buf_writer,CLOSE_PROTOCOL_ERROR).await?;
+            return Ok(())
+        }
+        match frame.opcode {
+            OP_CLOSE => {
+                let payload = read_payload(buf_reader,&frame,limits).await?;
+                record_upload(remote_addr,&upload);
+                let reply = if payload.len() >= 2 { &payload[..2] } else { &[] };
+                write_frame(buf_writer,OP_CLOSE,reply).await?;
+                return Ok(())
+            }
+            OP_PING => {
+                let payload = read_payload(buf_reader,&frame,limits).await?;
+                write_frame(buf_writer,OP_PONG,&payload).await?;
+            }
+            OP_PONG => {
+                discard_payload(buf_reader,frame.length,limits).await?;
+            }
+            OP_BINARY | OP_CONTINUATION if frame.opcode == OP_BINARY || message_opcode == OP_BINARY => {
+                //upload data, counted and thrown away
+                message_opcode = if frame.fin { OP_TEXT } else { OP_BINARY };
+                let start = Instant::now();
+                discard_payload(buf_reader,frame.length,limits).await?;
+                upload.start.get_or_insert(start);
+                upload.end = Some(Instant::now());
+                upload.bytes += frame.length;
+            }
+            OP_TEXT | OP_CONTINUATION => {
+                if message.len() as u64 + frame.length > MAX_TEXT_MESSAGE as u64 {
+                    write_close(buf_writer,CLOSE_TOO_BIG).await?;
+                    return Ok(())
+                }
+                message.extend(read_payload(buf_reader,&frame,limits).await?);
+                if !frame.fin {
+                    continue;
+                }
+                let command = String::from_utf8_lossy(&message).to_string();
+                message.clear();
+                handle_command(remote_addr,command.trim(),&mut upload,buf_writer).await?;
+            }
+            _ => {
+                write_close(buf_writer,CLOSE_PROTOCOL_ERROR).await?;
+                return Ok(())
+            }
+        }
+    }
+}
+
+async fn handle_command<W>(remote_addr : &str,command : &str,upload : &mut UploadCounter,buf_writer : &mut BufWriter<W>) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin
+{
+    let (name,argument) = command.split_once(' ').unwrap_or((command,""));
+    // every test counts against the request rate & traffic budget, not only the upgrade
+    if matches!(name,"download" | "upload") {
+        if let Err(retry_after) = limiter::check_request(remote_addr,Some("ws")) {
+            record_upload(remote_addr,upload);
+            write_frame(buf_writer,OP_TEXT,format!("error too many requests {retry_after}").as_bytes()).await?;
+            write_close(buf_writer,CLOSE_TRY_AGAIN_LATER).await?;
+            return Err(Error::other("client limits reached"))
+        }
+    }
+    match name {
+        "download" => {
+            // `download <ckSize>` or `download duration=<seconds>`
+            let mut query = Params::default();
+            match argument.split_once('=') {
+                Some(("duration",duration)) => query.insert("duration".to_string(),duration.to_string()),
+                _ => query.insert("ckSize".to_string(),argument.to_string())
+            }
+            let Some(limit) = get_download_limit(&query) else {
+                return write_frame(buf_writer,OP_TEXT,b"error invalid download size").await
+            };
+            let mut garbage = GarbageSource::new(limit);
+            let start = Instant::now();
+            let mut sent = 0;
+            while let Some(chunk) = garbage.next_chunk() {
+                let written = async {
+                    buf_writer.write_all(&frame_header(OP_BINARY,chunk.len())).await?;
+                    buf_writer.write_all(&chunk).await
+                }.await;
+                if let Err(e) = written {
+                    measure::record_download(remote_addr,sent,start,Instant::now());
+                    return Err(e)
+                }
+                sent += chunk.len() as u64;
+            }
+            buf_writer.flush().await?;
+            measure::record_download(remote_addr,sent,start,Instant::now());
+            write_frame(buf_writer,OP_TEXT,b"done").await
+        }
+        "upload" => {
+            // data of an upload that never ended still uses the traffic budget
+            limiter::record_traffic(remote_addr,upload.bytes);
+            *upload = UploadCounter::default();
+            Ok(())
+        }
+        "upload_end" => {
+            record_upload(remote_addr,upload);
+            let report = upload.report();
+            *upload = UploadCounter::default();
+            write_frame(buf_writer,OP_TEXT,report.as_bytes()).await
+        }
+        "ping" => {
+            let pong = format!("pong {} {}",argument,get_current_millis());
+            write_frame(buf_writer,OP_TEXT,pong.as_bytes()).await
+        }
+        _ => {
+            write_frame(buf_writer,OP_TEXT,b"error unknown command").await
+        }
+    }
+}
+
+fn record_upload(remote_addr : &str,upload : &UploadCounter) {
+    if let (Some(start),Some(end)) = (upload.start,upload.end) {
+        if upload.bytes > 0 {
+            measure::record_upload(remote_addr,upload.bytes,start,end);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
 use log::warn;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -6,6 +7,7 @@ use crate::config::SERVER_CONFIG;
 use crate::http::http_client::HttpClient;
 use crate::ip::mmdb::mmdb_reader::MMDBReader;
 use crate::ip::mmdb::mmdb_record::MMDBResult;
+use crate::results::metrics;
 
 #[allow(non_snake_case)]
 #[derive(Serialize, Deserialize)]
@@ -115,7 +117,10 @@ impl IPInfo {
 
     fn get_isp_info_from_db(ip : &str) -> Option<MMDBResult> {
         if let Some(mut ipdb_reader) = MMDBReader::from("country_asn.mmdb") {
-            return ipdb_reader.lookup(ip)
+            let lookup_start = Instant::now();
+            let isp_info = ipdb_reader.lookup(ip);
+            metrics::record_ip_lookup("mmdb",lookup_start.elapsed());
+            return isp_info
         }
         warn!("Unable to open country asn database file");
         None
@@ -127,6 +132,13 @@ impl IPInfo {
         if ip_info_token.is_empty() {
             return None
         }
+        let lookup_start = Instant::now();
+        let isp_info = Self::query_ipinfo_api(ip,&ip_info_token).await;
+        metrics::record_ip_lookup("ipinfo",lookup_start.elapsed());
+        isp_info
+    }
+
+    async fn query_ipinfo_api(ip : &str,ip_info_token : &str) -> Option<String> {
         if let Ok(mut client) = HttpClient::open("https://ipinfo.io").await {
             let request = format!(
                 "GET /{}/json?token={} HTTP/1.1\r\n\
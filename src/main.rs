@@ -25,6 +25,7 @@ fn main() -> std::io::Result<()> {
         error!("{e}");
         std::process::exit(1)
     }
+    http::compression::init_precompressed_assets();
 
     //init database
     let database = database::init();
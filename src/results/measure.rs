@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use crate::http::limiter;
+use crate::results::metrics;
+
+/*Server side measurement of garbage & empty traffic, grouped per client ip into test sessions*/
+
+const SESSION_TIMEOUT : Duration = Duration::from_secs(120);
+
+static SESSIONS: OnceLock<Mutex<HashMap<String,TestSession>>> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Transfer {
+    bytes : u64,
+    first_start : Option<Instant>,
+    last_end : Option<Instant>
+}
+
+impl Transfer {
+    fn add(&mut self,bytes : u64,start : Instant,end : Instant) {
+        self.bytes += bytes;
+        if self.first_start.is_none_or(|first| start < first) {
+            self.first_start = Some(start);
+        }
+        if self.last_end.is_none_or(|last| end > last) {
+            self.last_end = Some(end);
+        }
+    }
+
+    // parallel streams are measured over the whole window from first start to last end
+    fn mbps(&self) -> Option<f64> {
+        let secs = self.last_end?.duration_since(self.first_start?).as_secs_f64();
+        if self.bytes == 0 || secs <= 0.0 {
+            return None
+        }
+        Some((self.bytes as f64 * 8.0) / secs / 1_000_000.0)
+    }
+}
+
+#[derive(Debug)]
+struct TestSession {
+    download : Transfer,
+    upload : Transfer,
+    last_seen : Instant
+}
+
+impl TestSession {
+    fn new() -> Self {
+        TestSession {
+            download: Transfer::default(),
+            upload: Transfer::default(),
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ServerMeasurement {
+    pub download_mbps : Option<f64>,
+    pub upload_mbps : Option<f64>
+}
+
+fn with_session<F>(client_ip : &str,action : F)
+where
+    F: FnOnce(&mut TestSession)
+{
+    let sessions = SESSIONS.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut sessions = sessions.lock().unwrap();
+    if !sessions.contains_key(client_ip) {
+        sessions.retain(|_,session| session.last_seen.elapsed() < SESSION_TIMEOUT);
+    }
+    let session = sessions.entry(client_ip.to_string()).or_insert_with(TestSession::new);
+    if session.last_seen.elapsed() >= SESSION_TIMEOUT {
+        *session = TestSession::new();
+    }
+    action(session);
+    session.last_seen = Instant::now();
+}
+
+pub fn record_download(client_ip : &str,bytes : u64,start : Instant,end : Instant) {
+    limiter::record_traffic(client_ip,bytes);
+    metrics::record_garbage_bytes(bytes);
+    with_session(client_ip,|session| {
+        // download after an upload means the client started a new test
+        if session.upload.bytes > 0 {
+            *session = TestSession::new();
+        }
+        session.download.add(bytes,start,end);
+    });
+}
+
+pub fn record_upload(client_ip : &str,bytes : u64,start : Instant,end : Instant) {
+    limiter::record_traffic(client_ip,bytes);
+    metrics::record_empty_bytes(bytes);
+    with_session(client_ip,|session| session.upload.add(bytes,start,end));
+}
+
+pub fn take_measurement(client_ip : &str) -> ServerMeasurement {
+    let Some(sessions) = SESSIONS.get() else {
+        return ServerMeasurement::default()
+    };
+    let session = sessions.lock().unwrap().remove(client_ip);
+    match session {
+        Some(session) if session.last_seen.elapsed() < SESSION_TIMEOUT => {
+            ServerMeasurement {
+                download_mbps: session.download.mbps(),
+                upload_mbps: session.upload.mbps(),
+            }
+        }
+        _ => ServerMeasurement::default()
+    }
+}
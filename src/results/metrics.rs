@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use http::StatusCode;
+use crate::config::ROUTES;
+use crate::http::limiter;
+use crate::http::response::Response;
+
+/*Prometheus text exposition (version 0.0.4) of request, traffic & test counters, served on `/{base_url}/metrics`*/
+
+// seconds
+const LOOKUP_BUCKETS : &[f64] = &[0.001,0.005,0.01,0.025,0.05,0.1,0.25,0.5,1.0,2.5,5.0];
+// megabits per second
+const SPEED_BUCKETS : &[f64] = &[1.0,5.0,10.0,25.0,50.0,100.0,250.0,500.0,1000.0,2500.0,5000.0,10000.0];
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+#[derive(Default)]
+struct Metrics {
+    requests : Mutex<BTreeMap<(&'static str,u16),u64>>,
+    garbage_bytes : AtomicU64,
+    empty_bytes : AtomicU64,
+    tls_handshake_failures : AtomicU64,
+    // (backend, success)
+    telemetry_inserts : Mutex<BTreeMap<(String,bool),u64>>,
+    ip_lookups : Mutex<BTreeMap<&'static str,Histogram>>,
+    reported_speeds : Mutex<BTreeMap<&'static str,Histogram>>
+}
+
+struct Histogram {
+    bounds : &'static [f64],
+    // per bucket, not cumulative
+    counts : Vec<u64>,
+    sum : f64,
+    count : u64
+}
+
+impl Histogram {
+    fn new(bounds : &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0;bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self,value : f64) {
+        if let Some(index) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self,output : &mut String,name : &str,label : &str) {
+        let mut cumulative = 0;
+        for (bound,count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(output,"{name}_bucket{{{label},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(output,"{name}_bucket{{{label},le=\"+Inf\"}} {}",self.count);
+        let _ = writeln!(output,"{name}_sum{{{label}}} {}",self.sum);
+        let _ = writeln!(output,"{name}_count{{{label}}} {}",self.count);
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+// route name of a request path, `assets` for static files
+pub fn route_name(path : &str) -> &'static str {
+    ROUTES.get().and_then(|routes| routes.get(path.trim())).copied().unwrap_or("assets")
+}
+
+// route name from ROUTES, `assets` for static files & `unknown` for unparsed requests
+pub fn record_request(route : &'static str,status : StatusCode) {
+    *metrics().requests.lock().unwrap().entry((route,status.as_u16())).or_default() += 1;
+}
+
+pub fn record_garbage_bytes(bytes : u64) {
+    metrics().garbage_bytes.fetch_add(bytes,Ordering::Relaxed);
+}
+
+pub fn record_empty_bytes(bytes : u64) {
+    metrics().empty_bytes.fetch_add(bytes,Ordering::Relaxed);
+}
+
+pub fn record_tls_handshake_failure() {
+    metrics().tls_handshake_failures.fetch_add(1,Ordering::Relaxed);
+}
+
+pub fn record_telemetry_insert(backend : &str,success : bool) {
+    *metrics().telemetry_inserts.lock().unwrap().entry((backend.to_string(),success)).or_default() += 1;
+}
+
+// source : `ipinfo` api or `mmdb` offline database
+pub fn record_ip_lookup(source : &'static str,duration : Duration) {
+    metrics().ip_lookups.lock().unwrap()
+        .entry(source)
+        .or_insert_with(|| Histogram::new(LOOKUP_BUCKETS))
+        .observe(duration.as_secs_f64());
+}
+
+// speeds reported by clients with their telemetry, in Mbps
+pub fn record_reported_speed(direction : &'static str,mbps : f64) {
+    if !mbps.is_finite() || mbps < 0.0 {
+        return;
+    }
+    metrics().reported_speeds.lock().unwrap()
+        .entry(direction)
+        .or_insert_with(|| Histogram::new(SPEED_BUCKETS))
+        .observe(mbps);
+}
+
+fn write_header(output : &mut String,name : &str,kind : &str,help : &str) {
+    let _ = writeln!(output,"# HELP {name} {help}");
+    let _ = writeln!(output,"# TYPE {name} {kind}");
+}
+
+fn render() -> String {
+    let metrics = metrics();
+    let mut output = String::new();
+
+    write_header(&mut output,"librespeed_http_requests_total","counter","HTTP requests by route and response status.");
+    for ((route,status),count) in metrics.requests.lock().unwrap().iter() {
+        let _ = writeln!(output,"librespeed_http_requests_total{{route=\"{route}\",status=\"{status}\"}} {count}");
+    }
+
+    write_header(&mut output,"librespeed_garbage_bytes_total","counter","Bytes served by download tests.");
+    let _ = writeln!(output,"librespeed_garbage_bytes_total {}",metrics.garbage_bytes.load(Ordering::Relaxed));
+
+    write_header(&mut output,"librespeed_empty_bytes_total","counter","Bytes received by upload tests.");
+    let _ = writeln!(output,"librespeed_empty_bytes_total {}",metrics.empty_bytes.load(Ordering::Relaxed));
+
+    write_header(&mut output,"librespeed_active_connections","gauge","Open client connections.");
+    let _ = writeln!(output,"librespeed_active_connections {}",limiter::open_connections());
+
+    write_header(&mut output,"librespeed_tls_handshake_failures_total","counter","Failed or timed out TLS handshakes.");
+    let _ = writeln!(output,"librespeed_tls_handshake_failures_total {}",metrics.tls_handshake_failures.load(Ordering::Relaxed));
+
+    let telemetry_inserts = metrics.telemetry_inserts.lock().unwrap();
+    let families = [
+        (true,"librespeed_telemetry_inserts_total","Telemetry results stored by database backend."),
+        (false,"librespeed_telemetry_insert_failures_total","Telemetry results the database backend failed to store.")
+    ];
+    for (success,name,help) in families {
+        write_header(&mut output,name,"counter",help);
+        for ((backend,_),count) in telemetry_inserts.iter().filter(|((_,inserted),_)| *inserted == success) {
+            let _ = writeln!(output,"{name}{{backend=\"{backend}\"}} {count}");
+        }
+    }
+    drop(telemetry_inserts);
+
+    write_header(&mut output,"librespeed_getip_lookup_duration_seconds","histogram","getIP ISP lookup latency by source.");
+    for (source,histogram) in metrics.ip_lookups.lock().unwrap().iter() {
+        histogram.write(&mut output,"librespeed_getip_lookup_duration_seconds",&format!("source=\"{source}\""));
+    }
+
+    write_header(&mut output,"librespeed_reported_speed_mbps","histogram","Download and upload speeds reported by clients with their telemetry.");
+    for (direction,histogram) in metrics.reported_speeds.lock().unwrap().iter() {
+        histogram.write(&mut output,"librespeed_reported_speed_mbps",&format!("direction=\"{direction}\""));
+    }
+
+    output
+}
+
+pub fn metrics_response() -> Response {
+    Response::builder(StatusCode::OK)
+        .header("Content-Type","text/plain; version=0.0.4; charset=utf-8")
+        .no_cache()
+        .text(&render())
+}
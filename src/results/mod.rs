@@ -4,6 +4,8 @@ use serde::{Deserialize, Serialize};
 
 pub mod telemetry;
 pub mod stats;
+pub mod measure;
+pub mod metrics;
 
 #[derive(Deserialize,Serialize, Debug,Clone)]
 pub struct TelemetryData {
@@ -19,6 +21,8 @@ pub struct TelemetryData {
     pub log : String,
     pub uuid : String,
     pub timestamp : i64,
+    pub server_download : String,
+    pub server_upload : String,
 }
 
 pub fn redact_hostname(s: &mut String, replacement: &str) {
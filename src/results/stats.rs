@@ -1,5 +1,6 @@
 use std::sync::Arc;
 use handlebars::Handlebars;
+use serde::Serialize;
 use serde_json::json;
 use tokio::sync::Mutex;
 use crate::config::{time, SERVER_CONFIG};
@@ -9,6 +10,36 @@ use crate::http::request::Request;
 use crate::http::response::Response;
 use crate::results::TelemetryData;
 
+// relative difference between client reported and server measured speed to flag a result
+const MISMATCH_RATIO : f64 = 0.25;
+
+#[derive(Serialize)]
+struct StatsEntry {
+    #[serde(flatten)]
+    data : TelemetryData,
+    download_mismatch : bool,
+    upload_mismatch : bool
+}
+
+impl From<TelemetryData> for StatsEntry {
+    fn from(data: TelemetryData) -> Self {
+        StatsEntry {
+            download_mismatch: is_mismatch(&data.download,&data.server_download),
+            upload_mismatch: is_mismatch(&data.upload,&data.server_upload),
+            data,
+        }
+    }
+}
+
+fn is_mismatch(client : &str,server : &str) -> bool {
+    match (client.parse::<f64>(),server.parse::<f64>()) {
+        (Ok(client),Ok(server)) if server > 0.0 => {
+            ((client - server).abs() / server) > MISMATCH_RATIO
+        }
+        _ => false
+    }
+}
+
 pub async fn handle_stat_page (request : &Request,database : &mut Arc<Mutex<dyn Database + Send>>) -> Response {
     let server_config = SERVER_CONFIG.get().unwrap();
     let redirect_path = format!("{}/stats",server_config.base_url);
@@ -83,7 +114,7 @@ pub async fn handle_stat_page (request : &Request,database : &mut Arc<Mutex<dyn
     let data = json!({
         "no_password": no_password,
         "logged_in": logged_in,
-        "telemetry_list" : telemetry_list
+        "telemetry_list" : telemetry_list.into_iter().map(StatsEntry::from).collect::<Vec<StatsEntry>>()
     });
 
     let rendered_html = handlebars.render("stats_page",&data);
@@ -147,6 +178,10 @@ const HTML_TEMPLATE : &str = r#"
 	td {
 		word-break: break-all;
 	}
+	td.mismatch {
+		color: #C00000;
+		font-weight: bold;
+	}
 </style>
 </head>
 <body>
@@ -170,7 +205,9 @@ const HTML_TEMPLATE : &str = r#"
 		<tr><th>IP and ISP Info</th><td>{{ this.ip_address }}<br/>{{ this.isp_info }}</td></tr>
 		<tr><th>User agent and locale</th><td>{{ this.user_agent }}<br/>{{ this.lang }}</td></tr>
 		<tr><th>Download speed</th><td>{{ this.download }}</td></tr>
+		<tr><th>Server measured download</th><td{{#if this.download_mismatch}} class="mismatch"{{/if}}>{{ this.server_download }}{{#if this.download_mismatch}} (differs from client){{/if}}</td></tr>
 		<tr><th>Upload speed</th><td>{{ this.upload }}</td></tr>
+		<tr><th>Server measured upload</th><td{{#if this.upload_mismatch}} class="mismatch"{{/if}}>{{ this.server_upload }}{{#if this.upload_mismatch}} (differs from client){{/if}}</td></tr>
 		<tr><th>Ping</th><td>{{ this.ping }}</td></tr>
 		<tr><th>Jitter</th><td>{{ this.jitter }}</td></tr>
 		<tr><th>Log</th><td>{{ this.log }}</td></tr>
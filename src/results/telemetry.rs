@@ -15,6 +15,7 @@ use crate::database::{Database, generate_uuid};
 use crate::http::request::Request;
 use crate::results;
 use crate::ip::ip_info::IPInfo;
+use crate::results::{measure, metrics};
 use crate::results::TelemetryData;
 
 pub async fn record_result (request : &Request, database : &mut Arc<Mutex<dyn Database + Send>>) -> std::io::Result<String> {
@@ -30,6 +31,8 @@ pub async fn record_result (request : &Request, database : &mut Arc<Mutex<dyn Da
     let jitter = request.form_data.get("jitter").unwrap_or(&default);
     let mut log = request.form_data.get("log").unwrap_or(&default).clone();
     let uuid = generate_uuid();
+    let measurement = measure::take_measurement(&request.remote_addr);
+    let format_mbps = |mbps : Option<f64>| mbps.map(|mbps| format!("{:.2}",mbps)).unwrap_or_default();
 
     let config = SERVER_CONFIG.get().unwrap();
     if config.redact_ip_addresses {
@@ -40,6 +43,13 @@ pub async fn record_result (request : &Request, database : &mut Arc<Mutex<dyn Da
         results::redact_all_ips(&mut log,"0.0.0.0");
     }
 
+    if let Ok(mbps) = dl.parse::<f64>() {
+        metrics::record_reported_speed("download",mbps);
+    }
+    if let Ok(mbps) = ul.parse::<f64>() {
+        metrics::record_reported_speed("upload",mbps);
+    }
+
     let mut database = database.lock().await;
     let insert_db = database.insert(TelemetryData {
         ip_address,
@@ -54,7 +64,10 @@ pub async fn record_result (request : &Request, database : &mut Arc<Mutex<dyn Da
         log: log.to_string(),
         uuid: uuid.to_string(),
         timestamp: get_current_millis(),
+        server_download: format_mbps(measurement.download_mbps),
+        server_upload: format_mbps(measurement.upload_mbps),
     });
+    metrics::record_telemetry_insert(&config.database_type,insert_db.is_ok());
     match insert_db {
         Ok(_) => {
             Ok(uuid)